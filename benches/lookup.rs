@@ -0,0 +1,56 @@
+extern crate adobe_cmap_parser;
+#[macro_use]
+extern crate criterion;
+
+use adobe_cmap_parser::{parse_cmap, CMap, CompiledCMap};
+use criterion::{black_box, BenchmarkId, Criterion};
+use std::fs;
+
+// Pseudo-random two byte codes, standing in for the text of a page
+fn text(len: usize) -> Vec<u8> {
+    let mut state: u32 = 0x1234_5678;
+    let mut bytes = Vec::with_capacity(2 * len);
+    for _ in 0..len {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let code = (state >> 8) as u16 % 0x9000 + 0x20;
+        bytes.push((code >> 8) as u8);
+        bytes.push(code as u8);
+    }
+    bytes
+}
+
+fn lookup(c: &mut Criterion) {
+    let text = text(4096);
+    let mut group = c.benchmark_group("lookup");
+    for name in &["UniJIS-UCS2-H", "UniGB-UCS2-H", "Adobe-Japan1-UCS2"] {
+        let contents = fs::read(format!("assets/adobe_cmaps/{}", name)).unwrap();
+        let indexed = parse_cmap(&contents).unwrap();
+        // Merging into an empty CMap drops the index
+        let mut linear = CMap::default();
+        linear.merge(&indexed);
+        let compiled = CompiledCMap::new(parse_cmap(&contents).unwrap());
+
+        group.bench_with_input(BenchmarkId::new("decode_to_cids/indexed", name), &indexed, |b, cmap| {
+            b.iter(|| cmap.decode_to_cids(black_box(&text)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode_to_cids/linear", name), &linear, |b, cmap| {
+            b.iter(|| cmap.decode_to_cids(black_box(&text)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode_to_cids/compiled", name), &compiled, |b, cmap| {
+            b.iter(|| cmap.decode_to_cids(black_box(&text)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode_to_string/indexed", name), &indexed, |b, cmap| {
+            b.iter(|| cmap.decode_to_string_lossy(black_box(&text)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode_to_string/linear", name), &linear, |b, cmap| {
+            b.iter(|| cmap.decode_to_string_lossy(black_box(&text)))
+        });
+        group.bench_with_input(BenchmarkId::new("decode_to_string/compiled", name), &compiled, |b, cmap| {
+            b.iter(|| cmap.decode_to_string_lossy(black_box(&text)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lookup);
+criterion_main!(benches);
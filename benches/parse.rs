@@ -0,0 +1,34 @@
+extern crate adobe_cmap_parser;
+#[macro_use]
+extern crate criterion;
+
+use adobe_cmap_parser::parse_cmap;
+use criterion::{black_box, BenchmarkId, Criterion, Throughput};
+use std::fs;
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    for name in &["UniCNS-UCS2-H", "Adobe-Japan1-UCS2", "90ms-RKSJ-H"] {
+        let contents = fs::read(format!("assets/adobe_cmaps/{}", name)).unwrap();
+        group.throughput(Throughput::Bytes(contents.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &contents, |b, contents| {
+            b.iter(|| parse_cmap(black_box(contents)).unwrap())
+        });
+    }
+
+    let mut all = Vec::new();
+    for entry in fs::read_dir("assets/adobe_cmaps").unwrap() {
+        all.push(fs::read(entry.unwrap().path()).unwrap());
+    }
+    group.throughput(Throughput::Bytes(all.iter().map(|c| c.len() as u64).sum()));
+    group.sample_size(10);
+    group.bench_function("all", |b| {
+        b.iter(|| for contents in &all {
+            parse_cmap(black_box(contents)).unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
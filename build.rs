@@ -0,0 +1,49 @@
+#[cfg(feature = "predefined")]
+extern crate flate2;
+
+fn main() {
+    #[cfg(feature = "predefined")]
+    predefined::embed();
+}
+
+// Compresses the CMaps in assets/adobe_cmaps into OUT_DIR and generates
+// the table that `src/predefined.rs` includes.
+#[cfg(feature = "predefined")]
+mod predefined {
+    use flate2::Compression;
+    use flate2::write::DeflateEncoder;
+    use std::env;
+    use std::fmt::Write as FmtWrite;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::Path;
+
+    const CMAP_DIR: &str = "assets/adobe_cmaps";
+
+    pub fn embed() {
+        println!("cargo:rerun-if-changed={}", CMAP_DIR);
+
+        let out_dir = env::var("OUT_DIR").unwrap();
+        let cmap_dir = Path::new(&out_dir).join("adobe_cmaps");
+        fs::create_dir_all(&cmap_dir).unwrap();
+
+        let mut names: Vec<String> = fs::read_dir(CMAP_DIR).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        // Sorted so that lookups can use a binary search
+        names.sort();
+
+        let mut table = String::from("static PREDEFINED: &[(&str, &[u8])] = &[\n");
+        for name in &names {
+            let contents = fs::read(Path::new(CMAP_DIR).join(name)).unwrap();
+            let compressed_path = cmap_dir.join(format!("{}.deflate", name));
+            let mut encoder = DeflateEncoder::new(File::create(&compressed_path).unwrap(), Compression::best());
+            encoder.write_all(&contents).unwrap();
+            encoder.finish().unwrap();
+            writeln!(table, "    ({:?}, include_bytes!({:?})),", name, compressed_path.to_str().unwrap()).unwrap();
+        }
+        table.push_str("];\n");
+
+        fs::write(Path::new(&out_dir).join("predefined.rs"), table).unwrap();
+    }
+}
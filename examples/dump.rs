@@ -1,8 +1,4 @@
 extern crate adobe_cmap_parser;
-use adobe_cmap_parser::*;
-use std::fs::File;
-use std::io::{Read, BufReader};
-use std::path::Path;
 
 
 fn main() {
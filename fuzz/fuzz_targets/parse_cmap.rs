@@ -0,0 +1,13 @@
+#![no_main]
+
+use adobe_cmap_parser::parse_cmap;
+use libfuzzer_sys::fuzz_target;
+
+// Any input must either parse or fail with an error, and the resulting
+// CMap must be able to decode any bytes
+fuzz_target!(|data: &[u8]| {
+    if let Ok(cmap) = parse_cmap(data) {
+        cmap.decode_to_cids(data);
+        cmap.decode_to_string_lossy(data);
+    }
+});
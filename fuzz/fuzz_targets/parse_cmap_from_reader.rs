@@ -0,0 +1,21 @@
+#![no_main]
+
+use adobe_cmap_parser::parse_cmap_from_reader;
+use libfuzzer_sys::fuzz_target;
+use std::io::{self, Read};
+
+// Hands out the input a few bytes at a time, so that tokens span reads
+struct ChunkedReader<'a>(&'a [u8]);
+
+impl<'a> Read for ChunkedReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(7).min(self.0.len());
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_cmap_from_reader(ChunkedReader(data));
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use adobe_cmap_parser::parse_cmap_lenient;
+use libfuzzer_sys::fuzz_target;
+
+// Every problem of an input read from memory can be repaired, so parsing
+// never fails
+fuzz_target!(|data: &[u8]| {
+    let (cmap, _) = parse_cmap_lenient(data).unwrap();
+    cmap.decode_to_cids(data);
+    cmap.decode_to_string_lossy(data);
+});
@@ -0,0 +1,250 @@
+use std::sync::Arc;
+
+use {CharCode, Cid, CidLookup, CMap, CodeStatus};
+use decode::{self, Lookup, UnmappedPolicy};
+use error::{Result, CMapError};
+
+/// The lookups for every code of one length, indexed by the code's value.
+#[derive(Clone, Debug)]
+struct Table {
+    valid: Vec<bool>,
+    cids: Vec<CidLookup>,
+    // The text of code `i` is `text[text_ends[i]..text_ends[i + 1]]`
+    mapped: Vec<bool>,
+    text_ends: Vec<u32>,
+    text: String,
+}
+
+impl Table {
+    fn new(cmap: &CMap, len: u8) -> Table {
+        let count = 1usize << (8 * len);
+        let mut table = Table {
+            valid: Vec::with_capacity(count),
+            cids: Vec::with_capacity(count),
+            mapped: Vec::with_capacity(count),
+            text_ends: Vec::with_capacity(count + 1),
+            text: String::new(),
+        };
+        table.text_ends.push(0);
+        for value in 0..count as u32 {
+            let code = CharCode::new(value, len);
+            let bytes = &value.to_be_bytes()[4 - len as usize..];
+            table.valid.push(cmap.codespace_ranges.iter().any(|r| r.in_range(bytes)));
+            table.cids.push(cmap.lookup_cid(code));
+            table.mapped.push(cmap.push_unicode(code, &mut table.text));
+            table.text_ends.push(table.text.len() as u32);
+        }
+        table
+    }
+
+    fn unicode(&self, value: u32) -> Option<&str> {
+        let value = value as usize;
+        if self.mapped[value] {
+            Some(&self.text[self.text_ends[value] as usize..self.text_ends[value + 1] as usize])
+        } else {
+            None
+        }
+    }
+}
+
+/// A CMap with flat lookup tables for its one- and two-byte codes.
+///
+/// Where `CMap` keeps its mappings sparse, `CompiledCMap` precomputes the
+/// codespace membership, CID and text of every one- and two-byte code, so
+/// that decoding those takes a single array access per code. Codes of
+/// three or four bytes are looked up in the wrapped `CMap` as usual.
+///
+/// Tables are only built for the code lengths of the codespace ranges, so
+/// a CMap without one-byte codes gets no one-byte table. The two-byte tables take about a megabyte, so compiling is
+/// worthwhile for CMaps that decode a lot of text, such as the CMaps of
+/// the fonts of a document.
+///
+/// All lookups give the same results as those of the wrapped `CMap`.
+#[derive(Clone, Debug)]
+pub struct CompiledCMap {
+    cmap: Arc<CMap>,
+    one_byte: Option<Table>,
+    two_byte: Option<Table>,
+}
+
+impl CompiledCMap {
+    pub fn new<C: Into<Arc<CMap>>>(cmap: C) -> CompiledCMap {
+        let cmap = cmap.into();
+        let table = |len| {
+            if cmap.codespace_ranges.iter().any(|r| r.len == len as usize) {
+                Some(Table::new(&cmap, len))
+            } else {
+                None
+            }
+        };
+        CompiledCMap {
+            one_byte: table(1),
+            two_byte: table(2),
+            cmap,
+        }
+    }
+
+    /// Returns the CMap the tables were built from.
+    pub fn cmap(&self) -> &Arc<CMap> {
+        &self.cmap
+    }
+
+    /// Returns the table with `code`, if there is one. Codes whose value
+    /// is too large for their length aren't in any table and are left to
+    /// the wrapped `CMap`.
+    fn table(&self, code: CharCode) -> Option<&Table> {
+        let table = match code.len {
+            1 => self.one_byte.as_ref(),
+            2 => self.two_byte.as_ref(),
+            _ => None
+        };
+        table.filter(|_| code.value >> (8 * code.len) == 0)
+    }
+
+    /// See `CMap::split_code`.
+    pub fn split_code(&self, bytes: &[u8]) -> Option<(CharCode, CodeStatus)> {
+        let first = *bytes.first()? as u32;
+        if let Some(ref table) = self.one_byte {
+            if table.valid[first as usize] {
+                return Some((CharCode::new(first, 1), CodeStatus::Valid));
+            }
+        }
+        if let (Some(table), Some(&second)) = (self.two_byte.as_ref(), bytes.get(1)) {
+            let value = first << 8 | second as u32;
+            if table.valid[value as usize] {
+                return Some((CharCode::new(value, 2), CodeStatus::Valid));
+            }
+        }
+        self.cmap.split_code(bytes)
+    }
+
+    /// See `CMap::codepoint_to_cid`.
+    pub fn codepoint_to_cid(&self, code: CharCode) -> Cid {
+        self.lookup_cid(code).cid()
+    }
+
+    /// See `CMap::lookup_cid`.
+    pub fn lookup_cid(&self, code: CharCode) -> CidLookup {
+        match self.table(code) {
+            Some(table) => table.cids[code.value as usize],
+            None => self.cmap.lookup_cid(code)
+        }
+    }
+
+    /// See `CMap::codepoint_to_unicode`.
+    pub fn codepoint_to_unicode(&self, code: CharCode) -> Result<String> {
+        match self.table(code) {
+            Some(table) => table.unicode(code.value).map(str::to_owned)
+                .ok_or(CMapError::UnmappedCode(code)),
+            None => self.cmap.codepoint_to_unicode(code)
+        }
+    }
+
+    /// See `CMap::decode_to_string`.
+    pub fn decode_to_string(&self, bytes: &[u8], policy: UnmappedPolicy) -> Result<String> {
+        decode::decode_to_string(self, bytes, policy)
+    }
+
+    /// See `CMap::decode_to_string_lossy`.
+    pub fn decode_to_string_lossy(&self, bytes: &[u8]) -> String {
+        decode::decode_to_string(self, bytes, UnmappedPolicy::Replace).unwrap_or_default()
+    }
+
+    /// See `CMap::decode_to_cids`.
+    pub fn decode_to_cids(&self, bytes: &[u8]) -> Vec<Cid> {
+        let mut cids = Vec::with_capacity(bytes.len());
+        self.decode_to_cids_into(bytes, &mut cids);
+        cids
+    }
+
+    /// See `CMap::decode_to_cids_into`.
+    pub fn decode_to_cids_into(&self, bytes: &[u8], cids: &mut Vec<Cid>) {
+        decode::decode_to_cids_into(self, bytes, cids)
+    }
+}
+
+impl From<CMap> for CompiledCMap {
+    fn from(cmap: CMap) -> CompiledCMap {
+        CompiledCMap::new(cmap)
+    }
+}
+
+impl Lookup for CompiledCMap {
+    fn split_code(&self, bytes: &[u8]) -> Option<(CharCode, CodeStatus)> {
+        CompiledCMap::split_code(self, bytes)
+    }
+
+    fn lookup_cid(&self, code: CharCode) -> CidLookup {
+        CompiledCMap::lookup_cid(self, code)
+    }
+
+    fn push_unicode(&self, code: CharCode, text: &mut String) -> bool {
+        match self.table(code) {
+            Some(table) => match table.unicode(code.value) {
+                Some(unicode) => { text.push_str(unicode); true },
+                None => false
+            },
+            None => self.cmap.push_unicode(code, text)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loader::CMapDirectoryLoader;
+
+    #[test]
+    fn same_as_cmap() {
+        let loader = CMapDirectoryLoader::new(vec!["assets/adobe_cmaps"]);
+        // Mixed one- and two-byte, two-byte, UCS2 ToUnicode and up to four bytes
+        for name in &["90ms-RKSJ-H", "UniJIS-UCS2-H", "Adobe-Japan1-UCS2", "UniJIS-UTF16-H"] {
+            let cmap = loader.load(name).unwrap();
+            let compiled = CompiledCMap::new(cmap.clone());
+
+            for &(len, count) in &[(1u8, 0x100u32), (2, 0x10000)] {
+                for value in 0..count {
+                    let code = CharCode::new(value, len);
+                    assert_eq!(compiled.lookup_cid(code), cmap.lookup_cid(code), "{} {}", name, code);
+                    assert_eq!(compiled.codepoint_to_unicode(code).ok(), cmap.codepoint_to_unicode(code).ok(), "{} {}", name, code);
+                }
+            }
+            // Values too large for their length, which `CharCode::new` allows
+            for &code in &[CharCode::new(0x100, 1), CharCode::new(0x8140, 1), CharCode::new(0x10000, 2)] {
+                assert_eq!(compiled.lookup_cid(code), cmap.lookup_cid(code), "{} {}", name, code);
+                assert_eq!(compiled.codepoint_to_unicode(code).ok(), cmap.codepoint_to_unicode(code).ok(), "{} {}", name, code);
+                let (mut text, mut expected) = (String::new(), String::new());
+                assert_eq!(Lookup::push_unicode(&compiled, code, &mut text), cmap.push_unicode(code, &mut expected));
+            }
+
+            let mut state: u32 = 1;
+            let bytes: Vec<u8> = (0..0x4000).map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            }).collect();
+            for offset in 0..bytes.len() {
+                assert_eq!(compiled.split_code(&bytes[offset..]), cmap.split_code(&bytes[offset..]));
+            }
+            assert_eq!(compiled.decode_to_cids(&bytes), cmap.decode_to_cids(&bytes));
+            assert_eq!(compiled.decode_to_string_lossy(&bytes), cmap.decode_to_string_lossy(&bytes));
+        }
+    }
+
+    #[test]
+    fn table_sizes() {
+        let sizes = |compiled: &CompiledCMap| {
+            (compiled.one_byte.as_ref().map(|t| t.valid.len()), compiled.two_byte.as_ref().map(|t| t.valid.len()))
+        };
+        let loader = CMapDirectoryLoader::new(vec!["assets/adobe_cmaps"]);
+        // Mixed one- and two-byte, two-byte, and two- and four-byte codes
+        for &(name, expected) in &[("90ms-RKSJ-H", (Some(0x100), Some(0x10000))),
+                                   ("UniJIS-UCS2-H", (None, Some(0x10000))),
+                                   ("UniJIS-UTF16-H", (None, Some(0x10000)))] {
+            assert_eq!(sizes(&CompiledCMap::new(loader.load(name).unwrap())), expected, "{}", name);
+        }
+        let one_byte = ::parse_cmap(b"1 begincodespacerange <00> <ff> endcodespacerange").unwrap();
+        assert_eq!(sizes(&CompiledCMap::new(one_byte)), (Some(0x100), None));
+        let four_byte = ::parse_cmap(b"1 begincodespacerange <00000000> <ffffffff> endcodespacerange").unwrap();
+        assert_eq!(sizes(&CompiledCMap::new(four_byte)), (None, None));
+    }
+}
@@ -0,0 +1,217 @@
+use {CharCode, Cid, CidLookup, CMap, CodeStatus};
+use error::{Result, CMapError};
+
+/// A character code within a byte string, as returned by `CMap::codes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CodeSpan {
+    pub code: CharCode,
+    /// The offset of the code's first byte in the byte string.
+    pub offset: usize,
+    pub status: CodeStatus,
+}
+
+/// What `CMap::decode_to_string` does with codes it can't map to Unicode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UnmappedPolicy {
+    /// Leave the code out of the decoded text.
+    Skip,
+    /// Decode the code as U+FFFD REPLACEMENT CHARACTER.
+    #[default]
+    Replace,
+    /// Fail with `CMapError::NoUnicodeMappingFound`.
+    Error,
+}
+
+/// Iterator over the character codes of a byte string, see `CMap::codes`.
+#[derive(Clone, Debug)]
+pub struct Codes<'a> {
+    cmap: &'a CMap,
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for Codes<'a> {
+    type Item = CodeSpan;
+
+    fn next(&mut self) -> Option<CodeSpan> {
+        let (code, status) = self.cmap.split_code(&self.bytes[self.offset..])?;
+        let span = CodeSpan { code, offset: self.offset, status };
+        self.offset += code.len as usize;
+        Some(span)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.bytes.len() - self.offset;
+        (remaining.div_ceil(4), Some(remaining))
+    }
+}
+
+impl CMap {
+    /// Splits `bytes`, e.g. a string shown with a font using this CMap,
+    /// into character codes according to the codespace ranges.
+    ///
+    /// Bytes that don't form a valid code are handled as described for
+    /// `split_code`, so the codes always cover all of `bytes`.
+    pub fn codes<'a>(&'a self, bytes: &'a [u8]) -> Codes<'a> {
+        Codes { cmap: self, bytes, offset: 0 }
+    }
+
+    /// Decodes `bytes` to text using the `bfchar` and `bfrange` mappings
+    /// of this CMap, which is typically the `ToUnicode` CMap of a font.
+    ///
+    /// The bytes are split into codes by `codes`. Codes that are only
+    /// partially or not at all within the codespace are still looked up,
+    /// as producers regularly get the codespace of `ToUnicode` CMaps wrong.
+    /// Codes without a mapping are handled according to `policy`.
+    pub fn decode_to_string(&self, bytes: &[u8], policy: UnmappedPolicy) -> Result<String> {
+        decode_to_string(self, bytes, policy)
+    }
+
+    /// Decodes `bytes` to the CIDs of the glyphs to show, see
+    /// `decode_to_cids_into`.
+    pub fn decode_to_cids(&self, bytes: &[u8]) -> Vec<Cid> {
+        let mut cids = Vec::with_capacity(bytes.len());
+        self.decode_to_cids_into(bytes, &mut cids);
+        cids
+    }
+
+    /// Decodes `bytes` and appends the CID of every code to `cids`.
+    ///
+    /// Valid codes are mapped through `lookup_cid`, which falls back to
+    /// the notdef mappings and then to CID 0. Codes only partially matching
+    /// the codespace use their notdef mapping or CID 0, and invalid codes
+    /// always use CID 0, as specified in section 9.7.6.3 of the PDF
+    /// specification.
+    pub fn decode_to_cids_into(&self, bytes: &[u8], cids: &mut Vec<Cid>) {
+        decode_to_cids_into(self, bytes, cids)
+    }
+
+    /// Decodes `bytes` to text like `decode_to_string`, replacing codes
+    /// without a mapping with U+FFFD REPLACEMENT CHARACTER.
+    pub fn decode_to_string_lossy(&self, bytes: &[u8]) -> String {
+        decode_to_string(self, bytes, UnmappedPolicy::Replace).unwrap_or_default()
+    }
+}
+
+/// The lookups decoding is built on, implemented by `CMap` and by
+/// `CompiledCMap` with its dense tables.
+pub trait Lookup {
+    fn split_code(&self, bytes: &[u8]) -> Option<(CharCode, CodeStatus)>;
+    fn lookup_cid(&self, code: CharCode) -> CidLookup;
+    fn push_unicode(&self, code: CharCode, text: &mut String) -> bool;
+}
+
+impl Lookup for CMap {
+    fn split_code(&self, bytes: &[u8]) -> Option<(CharCode, CodeStatus)> {
+        CMap::split_code(self, bytes)
+    }
+
+    fn lookup_cid(&self, code: CharCode) -> CidLookup {
+        CMap::lookup_cid(self, code)
+    }
+
+    fn push_unicode(&self, code: CharCode, text: &mut String) -> bool {
+        CMap::push_unicode(self, code, text)
+    }
+}
+
+pub fn decode_to_string<L: Lookup>(lookup: &L, bytes: &[u8], policy: UnmappedPolicy) -> Result<String> {
+    let mut text = String::with_capacity(bytes.len());
+    let mut offset = 0;
+    while let Some((code, _)) = lookup.split_code(&bytes[offset..]) {
+        if !lookup.push_unicode(code, &mut text) {
+            match policy {
+                UnmappedPolicy::Skip => {},
+                UnmappedPolicy::Replace => text.push('\u{FFFD}'),
+                UnmappedPolicy::Error => return Err(CMapError::NoUnicodeMappingFound { code, offset })
+            }
+        }
+        offset += code.len as usize;
+    }
+    Ok(text)
+}
+
+pub fn decode_to_cids_into<L: Lookup>(lookup: &L, bytes: &[u8], cids: &mut Vec<Cid>) {
+    let mut offset = 0;
+    while let Some((code, status)) = lookup.split_code(&bytes[offset..]) {
+        cids.push(match status {
+            CodeStatus::Valid => lookup.lookup_cid(code).cid(),
+            CodeStatus::Partial => match lookup.lookup_cid(code) {
+                CidLookup::Notdef(cid) => cid,
+                _ => 0
+            },
+            CodeStatus::Invalid => 0
+        });
+        offset += code.len as usize;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parse_cmap;
+
+    #[test]
+    fn decode_to_string() {
+        let cmap = parse_cmap(b"
+            1 begincodespacerange
+            <0000> <ffff>
+            endcodespacerange
+            2 beginbfchar
+            <0003> <0020>
+            <0010> <00660069>
+            endbfchar
+            1 beginbfrange
+            <0024> <003d> <0041>
+            endbfrange").unwrap();
+        let bytes = [0x00, 0x2b, 0x00, 0x28, 0x00, 0x03, 0x00, 0x10, 0x00, 0x01, 0x00, 0x2f];
+        assert_eq!(cmap.decode_to_string(&bytes, UnmappedPolicy::Skip).unwrap(), "HE fiL");
+        assert_eq!(cmap.decode_to_string(&bytes, UnmappedPolicy::Replace).unwrap(), "HE fi\u{FFFD}L");
+        assert_eq!(cmap.decode_to_string_lossy(&bytes), "HE fi\u{FFFD}L");
+        match cmap.decode_to_string(&bytes, UnmappedPolicy::Error) {
+            Err(CMapError::NoUnicodeMappingFound { code, offset }) => {
+                assert_eq!(code, CharCode::new(0x0001, 2));
+                assert_eq!(offset, 8);
+            },
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn decode_to_cids() {
+        let cmap = parse_cmap(b"
+            2 begincodespacerange
+            <00> <80>
+            <8140> <9ffc>
+            endcodespacerange
+            1 beginnotdefrange
+            <00> <1f> 1
+            endnotdefrange
+            2 begincidrange
+            <20> <7e> 1
+            <8140> <817e> 633
+            endcidrange").unwrap();
+        let bytes = [0x41, 0x0a, 0x81, 0x41, 0x80, 0x81, 0xff, 0xa0];
+        assert_eq!(cmap.decode_to_cids(&bytes), vec![34, 1, 634, 0, 0, 0]);
+
+        let mut cids = vec![5];
+        cmap.decode_to_cids_into(&[0x20], &mut cids);
+        assert_eq!(cids, vec![5, 1]);
+    }
+
+    #[test]
+    fn codes() {
+        let cmap = parse_cmap(b"
+            2 begincodespacerange
+            <00> <80>
+            <8140> <9ffc>
+            endcodespacerange").unwrap();
+        let codes: Vec<_> = cmap.codes(&[0x41, 0x81, 0x40, 0x81, 0xff, 0x81]).collect();
+        assert_eq!(codes, vec![
+            CodeSpan { code: CharCode::new(0x41, 1), offset: 0, status: CodeStatus::Valid },
+            CodeSpan { code: CharCode::new(0x8140, 2), offset: 1, status: CodeStatus::Valid },
+            CodeSpan { code: CharCode::new(0x81ff, 2), offset: 3, status: CodeStatus::Partial },
+            CodeSpan { code: CharCode::new(0x81, 1), offset: 5, status: CodeStatus::Partial },
+        ]);
+    }
+}
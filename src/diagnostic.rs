@@ -0,0 +1,32 @@
+use error::Location;
+use std::fmt;
+
+/// Content of a CMap ignored by `parse_cmap_with_diagnostics`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    /// An operator that isn't part of the CMap format.
+    UnknownOperator,
+    /// A reference to another CMap with `usecmap`, which isn't followed.
+    IgnoredUseCMap,
+    /// A `def` of a key that isn't part of the CMap format.
+    UnknownKey,
+    /// A `def` of a known key whose value has the wrong type.
+    UnexpectedType,
+    /// A `bfchar` or `bfrange` mapping to invalid UTF-16, such as a lone
+    /// surrogate, which was skipped.
+    InvalidUnicode,
+}
+
+/// A warning about a CMap that was parsed successfully.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    pub message: String,
+    pub location: Location,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.location)
+    }
+}
@@ -1,33 +1,170 @@
-use pom;
+use CharCode;
+use options::Limit;
+use std::fmt;
 use std::io;
 use std::result;
 use std::str;
 use std::string;
 pub type Result<T> = result::Result<T, CMapError>;
 
+/// Where in a CMap file an error was found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    /// The offset in bytes from the start of the file
+    pub offset: usize,
+    /// The line, starting at 1
+    pub line: usize,
+    /// The column in bytes, starting at 1
+    pub column: usize,
+    /// The text of the file from `offset` up to the end of the line,
+    /// shortened if it is long
+    pub snippet: String,
+}
+
+impl Location {
+    pub fn new(offset: usize, line: usize, column: usize) -> Location {
+        Location { offset, line, column, snippet: String::new() }
+    }
+
+    // Errors are created without a location when the token they are about
+    // isn't known yet, the parser fills it in
+    fn is_known(&self) -> bool {
+        self.line > 0
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.is_known() {
+            return f.write_str("an unknown location");
+        }
+        write!(f, "line {}, column {}", self.line, self.column)?;
+        if !self.snippet.is_empty() {
+            write!(f, ": `{}`", self.snippet)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Fail, Debug)]
 pub enum CMapError {
     #[fail(display = "Encountered unknown operator {}", _0)]
     UnknownOperator(String),
 
-    #[fail(display = "Encountered the type {}, but expected {}", found, expected)]
-    CMapType { expected: &'static str, found: &'static str },
+    #[fail(display = "Encountered the type {}, but expected {} at {}", found, expected, location)]
+    CMapType { expected: &'static str, found: &'static str, location: Location },
+
+    #[fail(display = "Encountered an array of size {}, but expected {} at {}", found, expected, location)]
+    InvalidArrayLength { expected: usize, found: usize, location: Location },
 
-    #[fail(display = "Encountered an array of size {}, but expected {}", found, expected)]
-    InvalidArrayLength { expected: usize, found: usize },
+    #[fail(display = "Encountered a character code of {} bytes, but codes must have 1 to 4 bytes at {}", len, location)]
+    InvalidCharCodeLength { len: usize, location: Location },
 
+    /// A code of the bytes given to `decode_to_string` has no mapping,
+    /// `offset` is where it starts.
+    #[fail(display = "No unicode mapping found for codepoint {} at offset {}", code, offset)]
+    NoUnicodeMappingFound { code: CharCode, offset: usize },
+
+    /// The code given to `codepoint_to_unicode` has no mapping.
     #[fail(display = "No unicode mapping found for codepoint {}", _0)]
-    NoUnicodeMappingFound(u32),
+    UnmappedCode(CharCode),
 
-    #[fail(display = "{}", _0)]
-    Utf16(#[cause] string::FromUtf16Error),
+    /// The location is that of the `usecmap` referencing the CMap, and
+    /// isn't known if the CMap was requested directly.
+    #[fail(display = "Could not find the CMap {} at {}", name, location)]
+    CMapNotFound { name: String, location: Location },
 
-    #[fail(display = "{}", _0)]
-    Utf8(#[cause] str::Utf8Error),
+    #[fail(display = "The CMap {} is used recursively through usecmap at {}", name, location)]
+    UseCMapCycle { name: String, location: Location },
 
-    #[fail(display = "{}", _0)]
-    Lexer(#[cause] pom::Error),
+    #[fail(display = "Using the CMap {} exceeds the maximum usecmap depth of {} at {}", name, max, location)]
+    UseCMapDepthExceeded { name: String, max: usize, location: Location },
+
+    #[fail(display = "{} at {}", cause, location)]
+    Utf16 { #[cause] cause: string::FromUtf16Error, location: Location },
+
+    #[fail(display = "{} at {}", cause, location)]
+    Utf8 { #[cause] cause: str::Utf8Error, location: Location },
+
+    #[fail(display = "{} at {}", message, location)]
+    Lexer { message: &'static str, location: Location },
+
+    #[fail(display = "The operator {} is missing its operand at {}", operator, location)]
+    MissingOperand { operator: String, location: Location },
+
+    #[fail(display = "Encountered {} without a matching {} at {}", found, expected, location)]
+    UnmatchedDelimiter { found: &'static str, expected: &'static str, location: Location },
+
+    #[fail(display = "The input ended in the middle of the CMap at {}", location)]
+    UnexpectedEndOfInput { location: Location },
+
+    #[fail(display = "The {} exceeds the limit of {} at {}", limit, max, location)]
+    LimitExceeded { limit: Limit, max: usize, location: Location },
 
     #[fail(display = "{}", _0)]
     Io(#[cause] io::Error),
-}
\ No newline at end of file
+}
+
+impl CMapError {
+    /// Returns where in the file the error was found, for errors about the
+    /// contents of a CMap file.
+    pub fn location(&self) -> Option<&Location> {
+        match *self {
+            CMapError::CMapType { ref location, .. } |
+            CMapError::InvalidArrayLength { ref location, .. } |
+            CMapError::InvalidCharCodeLength { ref location, .. } |
+            CMapError::Utf16 { ref location, .. } |
+            CMapError::Utf8 { ref location, .. } |
+            CMapError::Lexer { ref location, .. } |
+            CMapError::MissingOperand { ref location, .. } |
+            CMapError::UnmatchedDelimiter { ref location, .. } |
+            CMapError::UnexpectedEndOfInput { ref location } |
+            CMapError::LimitExceeded { ref location, .. } => Some(location),
+            CMapError::CMapNotFound { ref location, .. } |
+            CMapError::UseCMapCycle { ref location, .. } |
+            CMapError::UseCMapDepthExceeded { ref location, .. } if location.is_known() => Some(location),
+            _ => None
+        }
+    }
+
+    /// Fills in the location of the error if it isn't known yet, and the
+    /// snippet of its location using `snippet`.
+    pub(crate) fn locate<F: FnOnce(usize) -> String>(mut self, location: Location, snippet: F) -> CMapError {
+        let own_location = match self {
+            CMapError::CMapType { ref mut location, .. } |
+            CMapError::InvalidArrayLength { ref mut location, .. } |
+            CMapError::InvalidCharCodeLength { ref mut location, .. } |
+            CMapError::Utf16 { ref mut location, .. } |
+            CMapError::Utf8 { ref mut location, .. } |
+            CMapError::Lexer { ref mut location, .. } |
+            CMapError::MissingOperand { ref mut location, .. } |
+            CMapError::UnmatchedDelimiter { ref mut location, .. } |
+            CMapError::UnexpectedEndOfInput { ref mut location } |
+            CMapError::LimitExceeded { ref mut location, .. } |
+            CMapError::CMapNotFound { ref mut location, .. } |
+            CMapError::UseCMapCycle { ref mut location, .. } |
+            CMapError::UseCMapDepthExceeded { ref mut location, .. } => location,
+            _ => return self
+        };
+        if !own_location.is_known() {
+            *own_location = location;
+        }
+        if own_location.snippet.is_empty() {
+            own_location.snippet = snippet(own_location.offset);
+        }
+        self
+    }
+
+    /// Splits an error about the contents of a CMap into its message and
+    /// its location.
+    pub(crate) fn into_message(self) -> (String, Location) {
+        let location = self.location().cloned().unwrap_or_default();
+        let mut message = self.to_string();
+        let suffix = format!(" at {}", location);
+        if message.ends_with(&suffix) {
+            let len = message.len() - suffix.len();
+            message.truncate(len);
+        }
+        (message, location)
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+
+use {CMapRange, UnicodeRange};
+
+/// Implemented by the range types of a CMap so that they can be indexed.
+pub trait Range {
+    /// Returns the length, first and last value of the codes in the range.
+    fn bounds(&self) -> (u8, u32, u32);
+}
+
+impl Range for CMapRange {
+    fn bounds(&self) -> (u8, u32, u32) {
+        (self.len, self.from, self.to)
+    }
+}
+
+impl Range for UnicodeRange {
+    fn bounds(&self) -> (u8, u32, u32) {
+        (self.len, self.from, self.to)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Interval {
+    len: u8,
+    from: u32,
+    to: u32,
+    range: usize,
+}
+
+/// An index over a list of possibly overlapping ranges, which answers
+/// which range is the first to contain a code with a binary search.
+///
+/// Where ranges overlap, the earlier range takes precedence, exactly as in
+/// a linear scan. To achieve that the ranges are cut into sorted,
+/// non-overlapping intervals, each pointing at the range that wins there.
+#[derive(Clone, Debug)]
+pub struct RangeIndex {
+    intervals: Vec<Interval>,
+}
+
+impl RangeIndex {
+    pub fn new<R: Range>(ranges: &[R]) -> RangeIndex {
+        let mut intervals = Vec::new();
+        // For every code length, the merged spans of codes claimed so far,
+        // keyed by their start. Spans covered by a new range are merged
+        // into one, so that a range over many claimed intervals is only
+        // expensive once.
+        let mut covered: BTreeMap<(u8, u32), u32> = BTreeMap::new();
+
+        for (i, range) in ranges.iter().enumerate() {
+            let (len, from, to) = range.bounds();
+            if from > to {
+                continue;
+            }
+
+            // The span starting before `from` may already cover part of the range
+            let earlier = covered.range(..(len, from)).next_back()
+                .filter(|&(&(l, _), &t)| l == len && t >= from)
+                .map(|(&(_, f), &t)| (f, t));
+            let overlapping: Vec<(u32, u32)> = earlier.into_iter()
+                .chain(covered.range((len, from)..=(len, to)).map(|(&(_, f), &t)| (f, t)))
+                .collect();
+
+            // Claim the gaps between the spans that are already taken
+            let (mut merged_from, mut merged_to) = (from, to);
+            let mut next = from as u64;
+            for &(f, t) in &overlapping {
+                if (f as u64) > next {
+                    intervals.push(Interval { len, from: next as u32, to: f - 1, range: i });
+                }
+                next = next.max(t as u64 + 1);
+                covered.remove(&(len, f));
+                merged_from = merged_from.min(f);
+                merged_to = merged_to.max(t);
+            }
+            if next <= to as u64 {
+                intervals.push(Interval { len, from: next as u32, to, range: i });
+            }
+            covered.insert((len, merged_from), merged_to);
+        }
+
+        intervals.sort_unstable_by_key(|interval| (interval.len, interval.from));
+        RangeIndex { intervals }
+    }
+
+    /// Returns the index of the first range containing the code of length
+    /// `len` with the given value.
+    pub fn find(&self, len: u8, value: u32) -> Option<usize> {
+        let end = self.intervals.partition_point(|i| (i.len, i.from) <= (len, value));
+        let interval = self.intervals[..end].last()?;
+        if interval.len == len && value <= interval.to {
+            Some(interval.range)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    impl Range for (u8, u32, u32) {
+        fn bounds(&self) -> (u8, u32, u32) {
+            *self
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let ranges = [(1, 0x20, 0x7e), (1, 0x00, 0xff), (2, 0x8140, 0x817e), (1, 0x41, 0x41), (2, 0x8100, 0x81ff)];
+        let index = RangeIndex::new(&ranges);
+        for &len in &[1, 2, 3] {
+            for value in 0..0x10000 {
+                let linear = ranges.iter().position(|&(l, f, t)| l == len && f <= value && value <= t);
+                assert_eq!(index.find(len, value), linear, "{} {:x}", len, value);
+            }
+        }
+    }
+
+    #[test]
+    fn many_overlapping_ranges() {
+        // Each of the full ranges overlaps all of the small ones, which
+        // made building the index quadratic
+        let n = 30_000;
+        let ranges: Vec<_> = (0..n).map(|i| (2, 2 * i, 2 * i))
+            .chain((0..n).map(|_| (2, 0, 0xffff)))
+            .collect();
+        let index = RangeIndex::new(&ranges);
+        assert_eq!(index.intervals.len(), 2 * n as usize);
+        assert_eq!(index.find(2, 2 * 123), Some(123));
+        assert_eq!(index.find(2, 2 * 123 + 1), Some(n as usize));
+        assert_eq!(index.find(2, 0xffff), Some(n as usize));
+    }
+}
@@ -0,0 +1,303 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use error::{Result, CMapError, Location};
+use lexer::{Position, Token};
+
+/// A PostScript object, as kept on the operand stack and in dictionaries.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Object<'a> {
+    /// A number, boolean, string or name
+    Simple(Token<'a>),
+    /// The index of an array of the interpreter. Like in PostScript, copies
+    /// of the object share the array.
+    Array(usize),
+    /// The index of a dictionary of the interpreter, shared the same way
+    Dictionary(usize),
+    /// A procedure, whose contents are dropped as it is never executed
+    Procedure,
+    /// The start of an array or dictionary built with `[` or `<<`
+    Mark,
+}
+
+impl<'a> Object<'a> {
+    pub fn get_type(&self) -> &'static str {
+        match *self {
+            Object::Simple(ref token) => token.get_type(),
+            Object::Array(_) => "Array",
+            Object::Dictionary(_) => "Dictionary",
+            Object::Procedure => "Procedure",
+            Object::Mark => "Mark"
+        }
+    }
+
+    pub fn expect_type<T>(&self, expected: &'static str) -> Result<T> {
+        Err(CMapError::CMapType { expected, found: self.get_type(), location: Location::default() })
+    }
+
+    fn type_error(&self, expected: &'static str, position: Position) -> CMapError {
+        CMapError::CMapType { expected, found: self.get_type(), location: position.into() }
+    }
+}
+
+/// The entries of a dictionary, with the position of each value.
+pub type Dictionary<'a> = HashMap<Vec<u8>, (Object<'a>, Position)>;
+
+/// A `key value def` that was executed.
+pub struct Definition<'a> {
+    pub key: Vec<u8>,
+    pub key_position: Position,
+    pub value: Object<'a>,
+    pub value_position: Position,
+    /// The dictionary the key was defined in
+    pub dictionary: usize,
+}
+
+/// Names and strings can be used as keys, other keys are ignored.
+fn key(object: &Object) -> Option<Vec<u8>> {
+    match *object {
+        Object::Simple(ref name @ Token::Name(_)) => name.as_name().ok().map(Cow::into_owned),
+        Object::Simple(ref s) if s.is_string() => s.as_string().ok().map(Cow::into_owned),
+        _ => None
+    }
+}
+
+/// Runs the operators of a CMap file that work on the operand and
+/// dictionary stacks, which is the subset of PostScript needed to build the
+/// dictionary of a CMap. The operators producing its mappings are left to
+/// the parser.
+pub struct Interpreter<'a> {
+    operands: Vec<(Object<'a>, Position)>,
+    // The number of marks among the operands
+    marks: usize,
+    arrays: Vec<Vec<Object<'a>>>,
+    dictionaries: Vec<Dictionary<'a>>,
+    // Indices into `dictionaries`, the current dictionary last. The first
+    // one stands in for `userdict` and is never removed.
+    dictionary_stack: Vec<usize>,
+    // The number of open procedures and the position of the outermost one
+    procedures: usize,
+    procedure_position: Position,
+}
+
+impl<'a> Interpreter<'a> {
+    pub fn new() -> Interpreter<'a> {
+        Interpreter {
+            operands: Vec::new(),
+            marks: 0,
+            arrays: Vec::new(),
+            dictionaries: vec![Dictionary::new()],
+            dictionary_stack: vec![0],
+            procedures: 0,
+            procedure_position: Position::default()
+        }
+    }
+
+    /// Whether the tokens are part of a procedure, so that operators aren't
+    /// executed.
+    pub fn in_procedure(&self) -> bool {
+        self.procedures > 0
+    }
+
+    pub fn current_dictionary(&self) -> usize {
+        self.dictionary_stack[self.dictionary_stack.len() - 1]
+    }
+
+    /// The number of dictionaries on the dictionary stack besides `userdict`.
+    pub fn dictionary_depth(&self) -> usize {
+        self.dictionary_stack.len() - 1
+    }
+
+    pub fn dictionary(&self, index: usize) -> &Dictionary<'a> {
+        &self.dictionaries[index]
+    }
+
+    pub fn array(&self, index: usize) -> &[Object<'a>] {
+        &self.arrays[index]
+    }
+
+    pub fn top(&self) -> Option<&(Object<'a>, Position)> {
+        self.operands.last()
+    }
+
+    fn push_operand(&mut self, operand: (Object<'a>, Position)) {
+        if operand.0 == Object::Mark {
+            self.marks += 1;
+        }
+        self.operands.push(operand);
+    }
+
+    /// Pops the operand of `op`, which has to exist.
+    pub fn pop(&mut self, op: &[u8]) -> Result<(Object<'a>, Position)> {
+        let operand = self.operands.pop().ok_or_else(|| CMapError::MissingOperand {
+            operator: String::from_utf8_lossy(op).into_owned(),
+            location: Location::default()
+        })?;
+        if operand.0 == Object::Mark {
+            self.marks -= 1;
+        }
+        Ok(operand)
+    }
+
+    fn pop_dictionary(&mut self, op: &[u8]) -> Result<usize> {
+        match self.pop(op)? {
+            (Object::Dictionary(index), _) => Ok(index),
+            (other, position) => Err(other.type_error("Dictionary", position))
+        }
+    }
+
+    fn pop_to_mark(&mut self, found: &'static str, expected: &'static str) -> Result<Vec<(Object<'a>, Position)>> {
+        // Without the count, every unmatched delimiter would search the
+        // whole stack
+        if self.marks == 0 {
+            return Err(CMapError::UnmatchedDelimiter { found, expected, location: Location::default() });
+        }
+        let mark = self.operands.iter().rposition(|(object, _)| *object == Object::Mark).unwrap_or(0);
+        let objects = self.operands.split_off(mark + 1);
+        self.operands.pop();
+        self.marks -= 1;
+        Ok(objects)
+    }
+
+    fn new_dictionary(&mut self, dictionary: Dictionary<'a>) -> Object<'a> {
+        self.dictionaries.push(dictionary);
+        Object::Dictionary(self.dictionaries.len() - 1)
+    }
+
+    /// Looks a key up in the dictionaries on the dictionary stack.
+    pub fn lookup(&self, key: &[u8]) -> Option<&(Object<'a>, Position)> {
+        self.dictionary_stack.iter().rev().filter_map(|&index| self.dictionaries[index].get(key)).next()
+    }
+
+    /// Pushes a token that isn't an operator, building arrays, dictionaries
+    /// and procedures from their delimiters.
+    pub fn push(&mut self, token: Token<'a>, position: Position) -> Result<()> {
+        if self.in_procedure() {
+            match token {
+                Token::ProcedureStart => self.procedures += 1,
+                Token::ProcedureEnd => {
+                    self.procedures -= 1;
+                    if self.procedures == 0 {
+                        self.push_operand((Object::Procedure, self.procedure_position));
+                    }
+                },
+                _ => {}
+            }
+            return Ok(());
+        }
+        let object = match token {
+            Token::ArrayStart | Token::DictionaryStart => Object::Mark,
+            Token::ProcedureStart => {
+                self.procedures = 1;
+                self.procedure_position = position;
+                return Ok(());
+            },
+            Token::ArrayEnd => {
+                let elements = self.pop_to_mark("]", "[")?;
+                self.arrays.push(elements.into_iter().map(|(object, _)| object).collect());
+                Object::Array(self.arrays.len() - 1)
+            },
+            Token::DictionaryEnd => {
+                let entries = self.pop_to_mark(">>", "<<")?;
+                if entries.len() % 2 != 0 {
+                    return Err(CMapError::MissingOperand { operator: ">>".to_owned(), location: Location::default() });
+                }
+                let mut dictionary = Dictionary::new();
+                let mut entries = entries.into_iter();
+                while let (Some((key_object, _)), Some(value)) = (entries.next(), entries.next()) {
+                    if let Some(key) = key(&key_object) {
+                        dictionary.insert(key, value);
+                    }
+                }
+                self.new_dictionary(dictionary)
+            },
+            Token::ProcedureEnd => {
+                return Err(CMapError::UnmatchedDelimiter { found: "}", expected: "{", location: Location::default() });
+            },
+            token => Object::Simple(token)
+        };
+        self.push_operand((object, position));
+        Ok(())
+    }
+
+    /// Executes `key value def` in the current dictionary. Definitions of
+    /// keys that aren't names or strings are ignored.
+    pub fn def(&mut self, op: &[u8]) -> Result<Option<Definition<'a>>> {
+        let (value, value_position) = self.pop(op)?;
+        let (key_object, key_position) = self.pop(op)?;
+        let key = match key(&key_object) {
+            Some(key) => key,
+            None => return Ok(None)
+        };
+        let dictionary = self.current_dictionary();
+        self.dictionaries[dictionary].insert(key.clone(), (value.clone(), value_position));
+        Ok(Some(Definition { key, key_position, value, value_position, dictionary }))
+    }
+
+    /// Executes an operator other than `def`. Names that aren't operators
+    /// are looked up on the dictionary stack, and `false` is returned if
+    /// they aren't found.
+    pub fn execute(&mut self, op: &[u8], position: Position) -> Result<bool> {
+        match op {
+            b"begin" => {
+                let dictionary = self.pop_dictionary(op)?;
+                self.dictionary_stack.push(dictionary);
+            },
+            b"end" => {
+                if self.dictionary_stack.len() > 1 {
+                    self.dictionary_stack.pop();
+                }
+            },
+            b"dict" => {
+                // The capacity isn't needed, as dictionaries grow as needed
+                let (capacity, capacity_position) = self.pop(op)?;
+                if let Object::Simple(Token::Integer(_)) = capacity {} else {
+                    return Err(capacity.type_error("Integer", capacity_position));
+                }
+                let dictionary = self.new_dictionary(Dictionary::new());
+                self.push_operand((dictionary, position));
+            },
+            b"currentdict" => {
+                let dictionary = Object::Dictionary(self.current_dictionary());
+                self.push_operand((dictionary, position));
+            },
+            b"dup" => {
+                let top = self.pop(op)?;
+                self.push_operand(top.clone());
+                self.push_operand(top);
+            },
+            b"exch" => {
+                let top = self.pop(op)?;
+                let below = self.pop(op)?;
+                self.push_operand(top);
+                self.push_operand(below);
+            },
+            b"pop" => {
+                self.pop(op)?;
+            },
+            b"bind" => {},
+            b"findresource" => {
+                // The resources of other categories aren't available, an
+                // empty dictionary stands in for them
+                self.pop(op)?;
+                self.pop(op)?;
+                let resource = self.new_dictionary(Dictionary::new());
+                self.push_operand((resource, position));
+            },
+            b"defineresource" => {
+                self.pop(op)?;
+                let instance = self.pop(op)?;
+                self.pop(op)?;
+                self.push_operand(instance);
+            },
+            _ => {
+                // Procedures are pushed rather than executed
+                match self.lookup(op).map(|(value, _)| value.clone()) {
+                    Some(value) => self.push_operand((value, position)),
+                    None => return Ok(false)
+                }
+            }
+        }
+        Ok(true)
+    }
+}
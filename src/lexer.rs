@@ -1,195 +1,905 @@
-use pom::char_class::{alpha, hex_digit, oct_digit, multispace};
-use pom::{Parser, DataInput};
-use pom::parser::*;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cmp::{max, min};
+use std::io::{self, Read};
+use std::iter;
+use std::mem;
+use std::str;
 
-use std::str::FromStr;
+use error::{Result, CMapError, Location};
+use options::Limit;
 
-use error::{Result, CMapError};
-
-#[derive(Debug)]
-pub enum Value {
-    LiteralString(Vec<u8>),
-    Name(Vec<u8>),
-    Number(String),
+/// A token of a CMap file. Tokens borrow their bytes from the input where
+/// possible, the contents of strings and names are only decoded when asked
+/// for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token<'a> {
+    /// An integer, written in decimal or with a radix like `16#FF`
     Integer(i64),
-    Array(Vec<Value>),
-    Operator(String),
+    /// A real number, e.g. `10.003` or `1.0E-3`
+    Number(f64),
     Boolean(bool),
-    Dictionary(HashMap<String, Value>),
+    /// The bytes after the `/`, which may contain `#xx` escapes
+    Name(Cow<'a, [u8]>),
+    /// The bytes after the `//` of a name to be replaced by its value
+    ImmediateName(Cow<'a, [u8]>),
+    /// An executable name, such as `def` or `begincmap`
+    Operator(Cow<'a, [u8]>),
+    /// The bytes between the parentheses, which may contain escapes
+    LiteralString(Cow<'a, [u8]>),
+    /// The bytes between the angle brackets, hex digits and whitespace
+    HexString(Cow<'a, [u8]>),
+    /// The decoded bytes of an ASCII85 string `<~ ~>`
+    Ascii85String(Cow<'a, [u8]>),
+    ArrayStart,
+    ArrayEnd,
+    /// `{`, which starts a procedure whose tokens aren't executed
+    ProcedureStart,
+    ProcedureEnd,
+    // Dictionaries are not mentioned in the CMap spec but are produced by software like Cairo and Skia and supported other by readers
+    DictionaryStart,
+    DictionaryEnd,
 }
 
-impl Value {
-    pub fn as_literal_string(&self) -> Result<&[u8]> {
+impl<'a> Token<'a> {
+    /// Copies the bytes of the token, so that it no longer borrows the input.
+    pub fn into_owned(self) -> Token<'static> {
+        match self {
+            Token::Integer(i) => Token::Integer(i),
+            Token::Number(n) => Token::Number(n),
+            Token::Boolean(b) => Token::Boolean(b),
+            Token::Name(s) => Token::Name(Cow::Owned(s.into_owned())),
+            Token::ImmediateName(s) => Token::ImmediateName(Cow::Owned(s.into_owned())),
+            Token::Operator(s) => Token::Operator(Cow::Owned(s.into_owned())),
+            Token::LiteralString(s) => Token::LiteralString(Cow::Owned(s.into_owned())),
+            Token::HexString(s) => Token::HexString(Cow::Owned(s.into_owned())),
+            Token::Ascii85String(s) => Token::Ascii85String(Cow::Owned(s.into_owned())),
+            Token::ArrayStart => Token::ArrayStart,
+            Token::ArrayEnd => Token::ArrayEnd,
+            Token::ProcedureStart => Token::ProcedureStart,
+            Token::ProcedureEnd => Token::ProcedureEnd,
+            Token::DictionaryStart => Token::DictionaryStart,
+            Token::DictionaryEnd => Token::DictionaryEnd
+        }
+    }
+
+    /// Whether the token is a string of any of the three kinds.
+    pub fn is_string(&self) -> bool {
+        matches!(*self, Token::LiteralString(_) | Token::HexString(_) | Token::Ascii85String(_))
+    }
+
+    /// Returns the bytes of a literal, hexadecimal or ASCII85 string.
+    pub fn as_string(&self) -> Result<Cow<'_, [u8]>> {
         match *self {
-            Value::LiteralString(ref s) => Ok(s),
-            _ => self.expect_type(&"LiteralString")
+            Token::LiteralString(ref s) => Ok(unescape_literal_string(s)),
+            Token::HexString(ref s) => Ok(Cow::Owned(hex_bytes(s).collect())),
+            Token::Ascii85String(ref s) => Ok(Cow::Borrowed(s)),
+            _ => self.expect_type("LiteralString")
         }
     }
 
-    pub fn as_name(&self) -> Result<&[u8]> {
+    pub fn as_name(&self) -> Result<Cow<'_, [u8]>> {
         match *self {
-            Value::Name(ref s) => Ok(s),
-            _ => self.expect_type(&"Name")         }
+            Token::Name(ref s) => Ok(unescape_name(s)),
+            _ => self.expect_type("Name")
+        }
     }
 
     pub fn as_integer(&self) -> Result<i64> {
         match *self {
-            Value::Integer(ref i) => Ok(*i),
-            _ => self.expect_type(&"Integer")
+            Token::Integer(i) => Ok(i),
+            _ => self.expect_type("Integer")
         }
     }
 
     pub fn expect_type<T>(&self, expected: &'static str) -> Result<T> {
-        Err(CMapError::CMapType {expected, found: self.get_type()})
+        Err(CMapError::CMapType {expected, found: self.get_type(), location: Location::default()})
     }
 
-    fn get_type(&self) -> &'static str {
+    pub fn get_type(&self) -> &'static str {
         match *self {
-            Value::LiteralString(_) => "LiteralString",
-            Value::Name(_) => "Name",
-            Value::Number(_) => "Number",
-            Value::Integer(_) => "Integer",
-            Value::Array(_) => "Array",
-            Value::Operator(_) => "Operator",
-            Value::Boolean(_) => "Boolean",
-            Value::Dictionary(_) => "Dictionary"
+            Token::LiteralString(_) | Token::HexString(_) | Token::Ascii85String(_) => "LiteralString",
+            Token::Name(_) => "Name",
+            Token::ImmediateName(_) => "ImmediateName",
+            Token::Number(_) => "Number",
+            Token::Integer(_) => "Integer",
+            Token::ArrayStart | Token::ArrayEnd => "Array",
+            Token::ProcedureStart | Token::ProcedureEnd => "Procedure",
+            Token::Operator(_) => "Operator",
+            Token::Boolean(_) => "Boolean",
+            Token::DictionaryStart | Token::DictionaryEnd => "Dictionary"
         }
     }
 }
 
-fn hex_char() -> Parser<u8, u8> {
-    let number = is_a(hex_digit).repeat(2);
-    number.collect().convert(|v|u8::from_str_radix(&String::from_utf8(v).unwrap(), 16))
+fn is_whitespace(c: u8) -> bool {
+    matches!(c, b' ' | b'\t' | b'\n' | b'\r' | b'\0' | b'\x0C')
 }
 
-fn comment() -> Parser<u8, ()> {
-    sym(b'%') * none_of(b"\r\n").repeat(0..) * eol().discard()
+fn is_delimiter(c: u8) -> bool {
+    matches!(c, b'(' | b')' | b'<' | b'>' | b'[' | b']' | b'{' | b'}' | b'/' | b'%')
 }
 
-fn content_space() -> Parser<u8, ()> {
-    is_a(multispace).repeat(0..).discard()
+fn is_regular(c: u8) -> bool {
+    !is_whitespace(c) && !is_delimiter(c)
 }
 
-fn operator() -> Parser<u8, String> {
-    (is_a(alpha) | one_of(b"*'\"")).repeat(1..).convert(|v|String::from_utf8(v))
+fn hex_digit(c: u8) -> u8 {
+    match c {
+        b'0'..=b'9' => c - b'0',
+        b'a'..=b'f' => c - b'a' + 10,
+        _ => c - b'A' + 10
+    }
 }
 
-fn oct_char() -> Parser<u8, u8> {
-    let number = is_a(oct_digit).repeat(1..4);
-    number.collect().convert(|v|u8::from_str_radix(&String::from_utf8(v).unwrap(), 8))
+// `digits` holds two hex digits
+fn hex_byte(digits: &[u8]) -> u8 {
+    hex_digit(digits[0]) << 4 | hex_digit(digits[1])
 }
 
-fn escape_sequence() -> Parser<u8, Vec<u8>> {
-    sym(b'\\') *
-        ( sym(b'\\').map(|_| vec![b'\\'])
-            | sym(b'(').map(|_| vec![b'('])
-            | sym(b')').map(|_| vec![b')'])
-            | sym(b'n').map(|_| vec![b'\n'])
-            | sym(b'r').map(|_| vec![b'\r'])
-            | sym(b't').map(|_| vec![b'\t'])
-            | sym(b'b').map(|_| vec![b'\x08'])
-            | sym(b'f').map(|_| vec![b'\x0C'])
-            | oct_char().map(|c| vec![c])
-            | eol()     .map(|_| vec![])
-            | empty()   .map(|_| vec![])
-        )
+/// Decodes the digits of a hexadecimal string, which were checked by the
+/// lexer. Whitespace is skipped, and a missing final digit is taken to be
+/// 0, so `<901>` is `<9010>`.
+pub fn hex_bytes(digits: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    let mut digits = digits.iter().filter(|&&c| !is_whitespace(c)).map(|&c| hex_digit(c));
+    iter::from_fn(move || {
+        let high = digits.next()?;
+        Some(high << 4 | digits.next().unwrap_or(0))
+    })
 }
 
-fn nested_literal_string() -> Parser<u8, Vec<u8>> {
-    sym(b'(') *
-        ( none_of(b"\\()").repeat(1..)
-            | escape_sequence()
-            | call(nested_literal_string)
-        ).repeat(0..).map(|segments| {
-            let mut bytes = segments.into_iter().fold(
-                vec![b'('],
-                |mut bytes, mut segment| {
-                    bytes.append(&mut segment);
-                    bytes
-                });
-            bytes.push(b')');
-            bytes
-        })
-        - sym(b')')
+/// Whether `bytes` is a real number like `-.5`, `1.` or `6.02E23`.
+fn is_real(bytes: &[u8]) -> bool {
+    let is_digits = |digits: &[u8]| digits.iter().all(u8::is_ascii_digit);
+    let unsigned = match bytes.first() {
+        Some(b'+') | Some(b'-') => &bytes[1..],
+        _ => bytes
+    };
+    let (mantissa, exponent) = match unsigned.iter().position(|&c| c == b'e' || c == b'E') {
+        Some(e) => (&unsigned[..e], Some(&unsigned[e + 1..])),
+        None => (unsigned, None)
+    };
+    let (integer, fraction) = match mantissa.iter().position(|&c| c == b'.') {
+        Some(dot) => (&mantissa[..dot], Some(&mantissa[dot + 1..])),
+        None => (mantissa, None)
+    };
+    let fraction_len = fraction.map_or(0, <[u8]>::len);
+    if !is_digits(integer) || !fraction.map_or(true, is_digits) || integer.len() + fraction_len == 0 {
+        return false;
+    }
+    match exponent {
+        Some(exponent) => {
+            let exponent = match exponent.first() {
+                Some(b'+') | Some(b'-') => &exponent[1..],
+                _ => exponent
+            };
+            !exponent.is_empty() && is_digits(exponent)
+        },
+        // Without a dot it is an integer
+        None => fraction.is_some()
+    }
+}
+
+/// Decodes a group of five ASCII85 digits, which have `!` subtracted.
+fn ascii85_group(digits: &[u8; 5]) -> Option<[u8; 4]> {
+    digits.iter()
+        .try_fold(0u32, |value, &digit| value.checked_mul(85)?.checked_add(digit as u32))
+        .map(u32::to_be_bytes)
 }
 
-fn literal_string() -> Parser<u8, Vec<u8>> {
-    sym(b'(') *
-        ( none_of(b"\\()").repeat(1..)
-            | escape_sequence()
-            | nested_literal_string()
-        ).repeat(0..).map(|segments|segments.concat())
-        - sym(b')')
+fn unescape_name(name: &[u8]) -> Cow<'_, [u8]> {
+    if !name.contains(&b'#') {
+        return Cow::Borrowed(name);
+    }
+    let mut bytes = Vec::with_capacity(name.len());
+    let mut i = 0;
+    while i < name.len() {
+        if name[i] == b'#' && i + 3 <= name.len()
+            && name[i+1].is_ascii_hexdigit() && name[i+2].is_ascii_hexdigit() {
+            bytes.push(hex_byte(&name[i+1..i+3]));
+            i += 3;
+        } else {
+            bytes.push(name[i]);
+            i += 1;
+        }
+    }
+    Cow::Owned(bytes)
 }
 
-fn name() -> Parser<u8, Vec<u8>> {
-    sym(b'/') * (none_of(b" \t\n\r\x0C()<>[]{}/%#") | sym(b'#') * hex_char()).repeat(0..)
+fn unescape_literal_string(string: &[u8]) -> Cow<'_, [u8]> {
+    if !string.contains(&b'\\') {
+        return Cow::Borrowed(string);
+    }
+    let mut bytes = Vec::with_capacity(string.len());
+    let mut i = 0;
+    while i < string.len() {
+        let c = string[i];
+        i += 1;
+        if c != b'\\' {
+            bytes.push(c);
+            continue;
+        }
+        let escaped = match string.get(i) {
+            Some(&c) => c,
+            None => break
+        };
+        i += 1;
+        match escaped {
+            b'n' => bytes.push(b'\n'),
+            b'r' => bytes.push(b'\r'),
+            b't' => bytes.push(b'\t'),
+            b'b' => bytes.push(b'\x08'),
+            b'f' => bytes.push(b'\x0C'),
+            b'0'..=b'7' => {
+                let mut value = (escaped - b'0') as u32;
+                let digits = string[i..].iter().take(2).take_while(|c| (b'0'..=b'7').contains(*c)).count();
+                for &digit in &string[i..i + digits] {
+                    value = value * 8 + (digit - b'0') as u32;
+                }
+                i += digits;
+                bytes.push(value as u8);
+            },
+            // A backslash at the end of a line continues the string on the next one
+            b'\r' => {
+                if string.get(i) == Some(&b'\n') {
+                    i += 1;
+                }
+            },
+            b'\n' => {},
+            // Covers `\\`, `\(` and `\)`, other backslashes are ignored
+            c => bytes.push(c)
+        }
+    }
+    Cow::Owned(bytes)
 }
 
-fn integer() -> Parser<u8, i64> {
-    let number = one_of(b"+-").opt() + one_of(b"0123456789").repeat(1..);
-    number.collect().convert(|v|String::from_utf8(v)).convert(|s|i64::from_str(&s))
+/// Where a token starts in the file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize
 }
 
-fn number() -> Parser<u8, String> {
-    let number = one_of(b"+-").opt() +
-        ( (one_of(b"0123456789") - one_of(b"0123456789").repeat(0..).discard())
-            | (one_of(b"0123456789").repeat(1..) * sym(b'.') - one_of(b"0123456789").repeat(0..))
-            | sym(b'.') - one_of(b"0123456789").repeat(1..)
-        );
-    number.collect().convert(|v|String::from_utf8(v))
+impl From<Position> for Location {
+    fn from(position: Position) -> Location {
+        Location::new(position.offset, position.line, position.column)
+    }
+}
+
+/// Returns the position of `offset` in `input`.
+pub fn position_at(input: &[u8], offset: usize) -> Position {
+    let mut lines = Lines::new();
+    for (i, &c) in input[..offset].iter().enumerate() {
+        if c == b'\r' || c == b'\n' {
+            lines.line_break(c, i);
+        }
+    }
+    lines.position(offset)
+}
+
+/// Keeps track of the line the lexer is in. `\r\n`, `\r` and `\n` each
+/// end a line.
+#[derive(Clone, Copy, Debug)]
+struct Lines {
+    line: usize,
+    // The offset of the first byte of the line
+    line_start: usize,
+    // The offset following the last `\r`
+    cr_end: usize
+}
+
+impl Lines {
+    fn new() -> Lines {
+        Lines { line: 1, line_start: 0, cr_end: usize::MAX }
+    }
+
+    /// Notes the `\r` or `\n` at `offset` of the file.
+    fn line_break(&mut self, c: u8, offset: usize) {
+        if c == b'\r' || self.cr_end != offset {
+            self.line += 1;
+        }
+        if c == b'\r' {
+            self.cr_end = offset + 1;
+        }
+        self.line_start = offset + 1;
+    }
+
+    /// Returns the position of `offset`, which must be in the current line.
+    fn position(&self, offset: usize) -> Position {
+        Position { offset, line: self.line, column: offset - self.line_start + 1 }
+    }
+}
+
+const SNIPPET_LEN: usize = 40;
+
+/// Returns the start of `bytes` up to the end of the line, for showing
+/// where an error is.
+fn snippet(bytes: &[u8]) -> String {
+    let end = bytes.iter().take(SNIPPET_LEN).position(|&c| c == b'\r' || c == b'\n')
+        .unwrap_or_else(|| min(bytes.len(), SNIPPET_LEN));
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
 }
 
-fn space() -> Parser<u8, ()> {
-    ( one_of(b" \t\n\r\0\x0C").repeat(1..).discard()
-    ).repeat(0..).discard()
+// The regular characters of `bytes` from `pos`, if there are any
+fn keyword_text(bytes: &[u8], pos: usize) -> Option<&[u8]> {
+    let rest = bytes.get(pos..)?;
+    let len = rest.iter().position(|&c| !is_regular(c)).unwrap_or(rest.len());
+    Some(&rest[..len]).filter(|text| !text.is_empty())
+}
+
+/// A source of tokens for the parser, each with its position.
+pub trait Tokens<'a>: Iterator<Item = Result<(Token<'a>, Position)>> {
+    /// Returns the position up to which the file has been tokenized.
+    fn end_position(&mut self) -> Position;
+
+    /// Returns the text of the file at `offset`, if it is still available.
+    fn snippet(&self, offset: usize) -> String;
+
+    /// Returns the text of the number or executable name at `offset`, if
+    /// it is still available.
+    fn keyword_text(&self, offset: usize) -> Option<&[u8]>;
+
+    /// Takes the DSC comments like `%%Title: (Identity)` found before the
+    /// first token so far, without their `%%`.
+    fn take_header_comments(&mut self) -> Vec<Cow<'a, [u8]>>;
+}
+
+/// Splits a CMap file into tokens, skipping whitespace and comments.
+pub struct Lexer<'a> {
+    input: &'a [u8],
+    pos: usize,
+    // The offset of `input` in the file, for error messages
+    offset: usize,
+    lines: Lines,
+    token_start: Position,
+    max_depth: usize,
+    // Whether no token has been found yet
+    in_header: bool,
+    header_comments: Vec<Cow<'a, [u8]>>
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a [u8]) -> Lexer<'a> {
+        Lexer::with_lines(input, 0, Lines::new())
+    }
+
+    fn with_lines(input: &'a [u8], offset: usize, lines: Lines) -> Lexer<'a> {
+        Lexer {
+            input,
+            pos: 0,
+            offset,
+            lines,
+            token_start: Position::default(),
+            max_depth: usize::MAX,
+            in_header: true,
+            header_comments: Vec::new()
+        }
+    }
+
+    /// Limits how deeply parentheses can be nested in literal strings.
+    pub fn set_max_nesting_depth(&mut self, max: usize) {
+        self.max_depth = max;
+    }
+
+    fn current_position(&self) -> Position {
+        self.lines.position(self.offset + self.pos)
+    }
+
+    fn line_break(&mut self, c: u8) {
+        if c == b'\r' || c == b'\n' {
+            self.lines.line_break(c, self.offset + self.pos);
+        }
+    }
+
+    fn error<T>(&self, message: &'static str) -> Result<T> {
+        Err(CMapError::Lexer { message, location: self.current_position().into() })
+    }
+
+    fn unterminated<T>(&self, message: &'static str) -> Result<T> {
+        Err(CMapError::Lexer { message, location: self.token_start.into() })
+    }
+
+    fn skip_whitespace_and_comments(&mut self) {
+        while let Some(&c) = self.input.get(self.pos) {
+            if c == b'%' {
+                let start = self.pos;
+                while self.pos < self.input.len() && self.input[self.pos] != b'\r' && self.input[self.pos] != b'\n' {
+                    self.pos += 1;
+                }
+                let comment = &self.input[start..self.pos];
+                if self.in_header && comment.starts_with(b"%%") {
+                    self.header_comments.push(Cow::Borrowed(&comment[2..]));
+                }
+            } else if is_whitespace(c) {
+                self.line_break(c);
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn regular_chars(&mut self) -> &'a [u8] {
+        let start = self.pos;
+        while self.pos < self.input.len() && is_regular(self.input[self.pos]) {
+            self.pos += 1;
+        }
+        &self.input[start..self.pos]
+    }
+
+    fn literal_string(&mut self) -> Result<Token<'a>> {
+        let start = self.pos;
+        let mut depth = 0;
+        while let Some(&c) = self.input.get(self.pos) {
+            match c {
+                b'\\' => {
+                    self.pos += 1;
+                    if let Some(&escaped) = self.input.get(self.pos) {
+                        self.line_break(escaped);
+                    }
+                },
+                b'\r' | b'\n' => { self.line_break(c); },
+                b'(' => {
+                    depth += 1;
+                    // The string itself counts as one level
+                    if depth >= self.max_depth {
+                        return Err(CMapError::LimitExceeded {
+                            limit: Limit::NestingDepth,
+                            max: self.max_depth,
+                            location: self.current_position().into()
+                        });
+                    }
+                },
+                b')' if depth == 0 => {
+                    self.pos += 1;
+                    return Ok(Token::LiteralString(Cow::Borrowed(&self.input[start..self.pos - 1])));
+                },
+                b')' => { depth -= 1; },
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        self.unterminated("Unterminated literal string")
+    }
+
+    fn hex_string(&mut self) -> Result<Token<'a>> {
+        let start = self.pos;
+        while let Some(&c) = self.input.get(self.pos) {
+            if c == b'>' {
+                self.pos += 1;
+                return Ok(Token::HexString(Cow::Borrowed(&self.input[start..self.pos - 1])));
+            }
+            if is_whitespace(c) {
+                self.line_break(c);
+            } else if !c.is_ascii_hexdigit() {
+                return self.error("Invalid character in hexadecimal string");
+            }
+            self.pos += 1;
+        }
+        self.unterminated("Unterminated hexadecimal string")
+    }
+
+    fn ascii85_string(&mut self) -> Result<Token<'a>> {
+        let mut bytes = Vec::new();
+        let mut group = [0; 5];
+        let mut len = 0;
+        while let Some(&c) = self.input.get(self.pos) {
+            match c {
+                b'~' if self.input.get(self.pos + 1) == Some(&b'>') => {
+                    if len == 1 {
+                        return self.error("Invalid final group in ASCII85 string");
+                    }
+                    if len > 0 {
+                        // The final group is padded with the highest digit
+                        // and only has as many bytes as it has digits minus one
+                        for digit in &mut group[len..] {
+                            *digit = b'u' - b'!';
+                        }
+                        match ascii85_group(&group) {
+                            Some(decoded) => bytes.extend_from_slice(&decoded[..len - 1]),
+                            None => return self.error("Invalid final group in ASCII85 string")
+                        }
+                    }
+                    self.pos += 2;
+                    return Ok(Token::Ascii85String(Cow::Owned(bytes)));
+                },
+                b'z' if len == 0 => bytes.extend_from_slice(&[0; 4]),
+                b'!'..=b'u' => {
+                    group[len] = c - b'!';
+                    len += 1;
+                    if len == 5 {
+                        match ascii85_group(&group) {
+                            Some(decoded) => bytes.extend_from_slice(&decoded),
+                            None => return self.error("Invalid group in ASCII85 string")
+                        }
+                        len = 0;
+                    }
+                },
+                _ if is_whitespace(c) => self.line_break(c),
+                _ => return self.error("Invalid character in ASCII85 string")
+            }
+            self.pos += 1;
+        }
+        self.unterminated("Unterminated ASCII85 string")
+    }
+
+    /// Lexes a radix number like `16#FF`, returns `None` if `bytes` isn't one.
+    fn radix_number(&self, bytes: &[u8]) -> Option<Result<Token<'a>>> {
+        let hash = bytes.iter().position(|&c| c == b'#')?;
+        let (radix, digits) = (&bytes[..hash], &bytes[hash + 1..]);
+        if radix.is_empty() || radix.len() > 2 || !radix.iter().all(u8::is_ascii_digit) || digits.is_empty() {
+            return None;
+        }
+        // The radix is one or two ASCII digits
+        let radix = str::from_utf8(radix).ok()?.parse().ok()?;
+        if !(2..=36).contains(&radix) {
+            return None;
+        }
+        let mut value = Some(0i64);
+        for &c in digits {
+            let digit = (c as char).to_digit(radix)?;
+            value = value.and_then(|v| v.checked_mul(radix as i64)?.checked_add(digit as i64));
+        }
+        Some(match value {
+            Some(value) => Ok(Token::Integer(value)),
+            None => self.error("Integer out of range")
+        })
+    }
+
+    /// Lexes regular characters. What isn't a number according to the
+    /// syntax of PostScript is an executable name.
+    fn keyword(&mut self) -> Result<Token<'a>> {
+        let bytes = self.regular_chars();
+        if bytes == b"true" || bytes == b"false" {
+            return Ok(Token::Boolean(bytes == b"true"));
+        }
+        let digits = match bytes.first() {
+            Some(b'+') | Some(b'-') => &bytes[1..],
+            _ => bytes
+        };
+        // Numbers consist of ASCII characters
+        let text = || str::from_utf8(bytes).unwrap_or_default();
+        if !digits.is_empty() && digits.iter().all(u8::is_ascii_digit) {
+            // Integers that are too large become reals
+            return match text().parse() {
+                Ok(i) => Ok(Token::Integer(i)),
+                Err(_) => self.real(text())
+            };
+        }
+        if let Some(radix_number) = self.radix_number(bytes) {
+            return radix_number;
+        }
+        if is_real(bytes) {
+            return self.real(text());
+        }
+        Ok(Token::Operator(Cow::Borrowed(bytes)))
+    }
+
+    /// Converts the text of a real number, which has the syntax of one.
+    fn real(&self, text: &str) -> Result<Token<'a>> {
+        // Like in PostScript, reals that can't be represented are an error
+        match text.parse::<f64>() {
+            Ok(number) if number.is_finite() => Ok(Token::Number(number)),
+            _ => Err(CMapError::Lexer { message: "Real number out of range", location: self.token_start.into() })
+        }
+    }
+
+    fn token(&mut self) -> Result<Token<'a>> {
+        let c = self.input[self.pos];
+        self.pos += 1;
+        match c {
+            b'/' if self.input.get(self.pos) == Some(&b'/') => {
+                self.pos += 1;
+                Ok(Token::ImmediateName(Cow::Borrowed(self.regular_chars())))
+            },
+            b'/' => Ok(Token::Name(Cow::Borrowed(self.regular_chars()))),
+            b'(' => self.literal_string(),
+            b'<' if self.input.get(self.pos) == Some(&b'~') => {
+                self.pos += 1;
+                self.ascii85_string()
+            },
+            b'<' if self.input.get(self.pos) == Some(&b'<') => {
+                self.pos += 1;
+                Ok(Token::DictionaryStart)
+            },
+            b'<' => self.hex_string(),
+            b'>' if self.input.get(self.pos) == Some(&b'>') => {
+                self.pos += 1;
+                Ok(Token::DictionaryEnd)
+            },
+            b'[' => Ok(Token::ArrayStart),
+            b']' => Ok(Token::ArrayEnd),
+            b'{' => Ok(Token::ProcedureStart),
+            b'}' => Ok(Token::ProcedureEnd),
+            b')' | b'>' => {
+                self.pos -= 1;
+                self.error("Unexpected delimiter")
+            },
+            _ => {
+                self.pos -= 1;
+                self.keyword()
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Option<Result<(Token<'a>, Position)>> {
+        self.skip_whitespace_and_comments();
+        if self.pos >= self.input.len() {
+            return None;
+        }
+        self.in_header = false;
+        self.token_start = self.current_position();
+        let position = self.token_start;
+        Some(self.token().map(|token| (token, position)))
+    }
 }
 
-fn hexadecimal_string() -> Parser<u8, Vec<u8>> {
-    sym(b'<') * hex_char().repeat(0..) - sym(b'>')
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(Token<'a>, Position)>;
+
+    fn next(&mut self) -> Option<Result<(Token<'a>, Position)>> {
+        let token = self.next_token();
+        if let Some(Err(_)) = token {
+            // Stop after the first error instead of reporting it over and over
+            self.pos = self.input.len();
+        }
+        token
+    }
 }
 
-fn eol() -> Parser<u8, u8> {
-    sym(b'\r') * sym(b'\n') | sym(b'\n') | sym(b'\r')
+impl<'a> Tokens<'a> for Lexer<'a> {
+    fn end_position(&mut self) -> Position {
+        self.current_position()
+    }
+
+    fn snippet(&self, offset: usize) -> String {
+        match offset.checked_sub(self.offset) {
+            Some(pos) if pos <= self.input.len() => snippet(&self.input[pos..]),
+            _ => String::new()
+        }
+    }
+
+    fn keyword_text(&self, offset: usize) -> Option<&[u8]> {
+        keyword_text(self.input, offset.checked_sub(self.offset)?)
+    }
+
+    fn take_header_comments(&mut self) -> Vec<Cow<'a, [u8]>> {
+        mem::take(&mut self.header_comments)
+    }
 }
 
-// Dictionaries are not mentioned in the CMap spec but are produced by software like Cairo and Skia and supported other by readers
-fn dictionary() -> Parser<u8, HashMap<String, Value>> {
-    let entry = name() - space() + call(value);
-    let entries = seq(b"<<") * space() * entry.repeat(0..) - seq(b">>");
-    entries.map(|entries| entries.into_iter().fold(
-        HashMap::new(),
-        |mut dict: HashMap<String, Value>, (key, value)| { dict.insert(String::from_utf8(key).unwrap(), value); dict }
-    ))
+const READ_CHUNK_SIZE: usize = 8192;
+const SNIPPET_HISTORY: usize = 256;
+
+/// Splits a CMap file into tokens while reading it from `reader`. Only the
+/// unconsumed part of the file, and a few bytes before it, is buffered, so
+/// tokens own their bytes.
+pub struct ReaderLexer<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    pos: usize,
+    // The offset of `buffer` in the file
+    offset: usize,
+    // The lines up to `pos`
+    lines: Lines,
+    in_header: bool,
+    header_comments: Vec<Cow<'static, [u8]>>,
+    eof: bool,
+    failed: bool
 }
 
-fn array() -> Parser<u8, Vec<Value>> {
-    sym(b'[') * space() * call(value).repeat(0..) - sym(b']')
+impl<R: Read> ReaderLexer<R> {
+    pub fn new(reader: R) -> ReaderLexer<R> {
+        ReaderLexer {
+            reader,
+            buffer: Vec::new(),
+            pos: 0,
+            offset: 0,
+            lines: Lines::new(),
+            in_header: true,
+            header_comments: Vec::new(),
+            eof: false,
+            failed: false
+        }
+    }
+
+    /// Drops the consumed bytes from the buffer and reads the next chunk.
+    /// The last few consumed bytes are kept for error snippets.
+    fn fill_buffer(&mut self) -> Result<()> {
+        let consumed = self.pos.saturating_sub(SNIPPET_HISTORY);
+        self.buffer.drain(..consumed);
+        self.offset += consumed;
+        self.pos -= consumed;
+
+        // Growing with the buffer keeps re-lexing a long token cheap
+        let len = self.buffer.len();
+        self.buffer.resize(len + max(len, READ_CHUNK_SIZE), 0);
+        let read = loop {
+            match self.reader.read(&mut self.buffer[len..]) {
+                Ok(read) => break read,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => {
+                    self.buffer.truncate(len);
+                    return Err(CMapError::Io(e));
+                }
+            }
+        };
+        self.buffer.truncate(len + read);
+        self.eof = read == 0;
+        Ok(())
+    }
+
+    fn next_token(&mut self) -> Option<Result<(Token<'static>, Position)>> {
+        loop {
+            let (token, consumed, lines, in_header, comments) = {
+                let mut lexer = Lexer::with_lines(&self.buffer[self.pos..], self.offset + self.pos, self.lines);
+                lexer.in_header = self.in_header;
+                let token = lexer.next_token().map(|token| token.map(|(token, position)| (token.into_owned(), position)));
+                let comments: Vec<_> = lexer.header_comments.into_iter().map(|c| Cow::Owned(c.into_owned())).collect();
+                (token, lexer.pos, lexer.lines, lexer.in_header, comments)
+            };
+            // A token or an error running up to the end of the buffer may
+            // just be cut off by the chunk boundary. The lexer looks at most
+            // one byte past where it stops, e.g. for `>>`.
+            if self.eof || self.pos + consumed + 1 < self.buffer.len() {
+                self.pos += consumed;
+                self.lines = lines;
+                self.in_header = in_header;
+                self.header_comments.extend(comments);
+                return token;
+            }
+            if let Err(e) = self.fill_buffer() {
+                return Some(Err(e));
+            }
+        }
+    }
 }
 
+impl<R: Read> Iterator for ReaderLexer<R> {
+    type Item = Result<(Token<'static>, Position)>;
 
-fn value() -> Parser<u8, Value> {
-    ( seq(b"true").map(|_| Value::Boolean(true))
-    | seq(b"false").map(|_| Value::Boolean(false))
-    | integer().map(|v| Value::Integer(v))
-    | number().map(|v| Value::Number(v))
-    | name().map(|v| Value::Name(v))
-    | operator().map(|v| Value::Operator(v))
-    | literal_string().map(|v| Value::LiteralString(v))
-    | dictionary().map(|v| Value::Dictionary(v))
-    | hexadecimal_string().map(|v| Value::LiteralString(v))
-    | array().map(|v| Value::Array(v))
-    ) - content_space()
+    fn next(&mut self) -> Option<Result<(Token<'static>, Position)>> {
+        if self.failed {
+            return None;
+        }
+        let token = self.next_token();
+        if let Some(Err(_)) = token {
+            self.failed = true;
+        }
+        token
+    }
 }
 
+impl<R: Read> Tokens<'static> for ReaderLexer<R> {
+    fn end_position(&mut self) -> Position {
+        self.lines.position(self.offset + self.pos)
+    }
 
-fn lexems() -> Parser<u8,Vec<Value>>
-{
-    ( comment().repeat(0..) * content_space() * value()).repeat(1..)
+    fn take_header_comments(&mut self) -> Vec<Cow<'static, [u8]>> {
+        mem::take(&mut self.header_comments)
+    }
+
+    fn snippet(&self, offset: usize) -> String {
+        match offset.checked_sub(self.offset) {
+            Some(pos) if pos <= self.buffer.len() => snippet(&self.buffer[pos..]),
+            _ => String::new()
+        }
+    }
+
+    fn keyword_text(&self, offset: usize) -> Option<&[u8]> {
+        // Only the consumed part of the buffer holds whole tokens
+        keyword_text(&self.buffer[..self.pos], offset.checked_sub(self.offset)?)
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &[u8]) -> Vec<Token<'_>> {
+        Lexer::new(input).map(|t| t.map(|(token, _)| token)).collect::<Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn tokens_borrow_input() {
+        assert_eq!(tokens(b"/CMapVersion 10.003 def % comment\n1 begincidrange <8140> [(A) -2] << >> true"), vec![
+            Token::Name(Cow::Borrowed(b"CMapVersion")), Token::Number(10.003), Token::Operator(Cow::Borrowed(b"def")),
+            Token::Integer(1), Token::Operator(Cow::Borrowed(b"begincidrange")), Token::HexString(Cow::Borrowed(b"8140")),
+            Token::ArrayStart, Token::LiteralString(Cow::Borrowed(b"A")), Token::Integer(-2), Token::ArrayEnd,
+            Token::DictionaryStart, Token::DictionaryEnd, Token::Boolean(true)
+        ]);
+    }
+
+    #[test]
+    fn strings_and_names() {
+        assert_eq!(tokens(b"(a(b)c\\)\\101\\\nd)")[0].as_string().unwrap(), &b"a(b)c)Ad"[..]);
+        assert_eq!(tokens(b"<00fF>")[0].as_string().unwrap(), &[0x00, 0xff][..]);
+        assert_eq!(tokens(b"< 00 4\r\n1\t>")[0].as_string().unwrap(), &[0x00, 0x41][..]);
+        assert_eq!(tokens(b"<901>")[0].as_string().unwrap(), &[0x90, 0x10][..]);
+        assert_eq!(tokens(b"<>")[0].as_string().unwrap(), &[][..]);
+        assert_eq!(tokens(b"/A#20B")[0].as_name().unwrap(), &b"A B"[..]);
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(tokens(b"0 +17 -98 16#FF 8#1777 2#1000 36#Zz 99999999999999999999"), vec![
+            Token::Integer(0), Token::Integer(17), Token::Integer(-98), Token::Integer(255), Token::Integer(1023),
+            Token::Integer(8), Token::Integer(1295), Token::Number(1e20)
+        ]);
+        assert_eq!(tokens(b"-.002 34.5 -3.62 123.6e10 1.0E-5 1E6 -1. 0.0 +.5e+2"), vec![
+            Token::Number(-0.002), Token::Number(34.5), Token::Number(-3.62), Token::Number(123.6e10), Token::Number(1.0E-5),
+            Token::Number(1E6), Token::Number(-1.), Token::Number(0.0), Token::Number(50.0)
+        ]);
+        for number in &[&b"1e999"[..], b"-2.5E400", &[b'9'; 400]] {
+            let error = Lexer::new(number).next().unwrap().unwrap_err();
+            assert_eq!(error.to_string(), "Real number out of range at line 1, column 1");
+        }
+        // Anything else made of regular characters is an executable name
+        for name in &[&b"."[..], b"-", b"1e", b"e5", b"1.2.3", b"--1", b"16#", b"#FF", b"1#0", b"37#1", b"8#8", b"inf", b"nan"] {
+            assert_eq!(tokens(name), vec![Token::Operator(Cow::Borrowed(name))]);
+        }
+    }
+
+    #[test]
+    fn postscript_tokens() {
+        assert_eq!(tokens(b"/A //B {dup 1 {pop} if} <~87cURD]i,\"Ebo80~> <~z!!~> <~ 8 7\ncU R~>"), vec![
+            Token::Name(Cow::Borrowed(b"A")), Token::ImmediateName(Cow::Borrowed(b"B")), Token::ProcedureStart,
+            Token::Operator(Cow::Borrowed(b"dup")), Token::Integer(1), Token::ProcedureStart,
+            Token::Operator(Cow::Borrowed(b"pop")), Token::ProcedureEnd, Token::Operator(Cow::Borrowed(b"if")),
+            Token::ProcedureEnd, Token::Ascii85String(Cow::Borrowed(b"Hello World!")),
+            Token::Ascii85String(Cow::Borrowed(b"\0\0\0\0\0")), Token::Ascii85String(Cow::Borrowed(b"Hell"))
+        ]);
+        assert_eq!(tokens(b"<~~>")[0].as_string().unwrap(), &b""[..]);
+    }
+
+    // Hands out one byte per read, so that every token spans reads
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl<'a> Read for OneByteReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((&b, rest)) if !buf.is_empty() => {
+                    buf[0] = b;
+                    self.0 = rest;
+                    Ok(1)
+                },
+                _ => Ok(0)
+            }
+        }
+    }
 
-pub fn lexer(input: &[u8]) -> Result<Vec<Value>> {
-    lexems().parse(&mut DataInput::new(input)).map_err(CMapError::Lexer)
+    #[test]
+    fn reader_tokens_span_reads() {
+        let input = b"%!PS\n/Ordering (Ja(pa)n\\)1) def <<>> /A#20B 12 10.003 <00ff> [<8140>] <81 4\n> <~87cURD]i,\"Ebo80~> { 1.5e3 16#FF } //C beginbfchar %end";
+        let from_reader: Vec<_> = ReaderLexer::new(OneByteReader(input)).collect();
+        let from_slice: Vec<_> = Lexer::new(input).map(|t| t.map(|(token, position)| (token.into_owned(), position))).collect();
+        assert_eq!(format!("{:?}", from_reader), format!("{:?}", from_slice));
+    }
+
+    #[test]
+    fn positions() {
+        let positions: Vec<_> = Lexer::new(b"1 begincidchar\r\n<00>\n\r  % comment\n 1").map(|t| {
+            let (_, position) = t.unwrap();
+            (position.offset, position.line, position.column)
+        }).collect();
+        assert_eq!(positions, vec![(0, 1, 1), (2, 1, 3), (16, 2, 1), (35, 5, 2)]);
+
+        let positions: Vec<_> = Lexer::new(b"<00\n41> <42>").map(|t| t.unwrap().1.line).collect();
+        assert_eq!(positions, vec![1, 2]);
+
+        match Lexer::new(b"1 beginbfchar\n<01> (abc").last() {
+            Some(Err(CMapError::Lexer { location, .. })) => assert_eq!((location.line, location.column), (2, 6)),
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn errors() {
+        for input in &[&b"(abc"[..], b"<0g>", b"<00", b")", b"16#FFFFFFFFFFFFFFFFF", b"<~abc", b"<~a~>", b"<~uuuuu~>", b"<~v~>"] {
+            match Lexer::new(input).last() {
+                Some(Err(CMapError::Lexer { .. })) => {},
+                r => panic!("unexpected result {:?} for {:?}", r, input)
+            }
+        }
+    }
 }
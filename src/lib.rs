@@ -1,21 +1,47 @@
 extern crate failure;
-extern crate pom;
+#[cfg(feature = "predefined")]
+extern crate flate2;
 
 #[macro_use] extern crate failure_derive;
 
 use error::Result;
+use index::RangeIndex;
 use std::collections::HashMap;
-use std::cmp::min;
+use std::cmp::{max, min, Reverse};
+use std::fmt;
 
+mod compiled;
+mod decode;
+mod diagnostic;
 mod error;
+mod index;
+mod interpreter;
 mod lexer;
+mod loader;
+pub mod metadata;
+mod options;
 mod parser;
+mod repair;
+#[cfg(feature = "predefined")]
+mod predefined;
+mod resolver;
+mod visitor;
 
-pub use parser::parse_cmap;
-pub use error::CMapError;
+pub use compiled::CompiledCMap;
+pub use decode::{CodeSpan, Codes, UnmappedPolicy};
+pub use diagnostic::{Diagnostic, DiagnosticKind};
+pub use loader::CMapDirectoryLoader;
+pub use metadata::{CidSystemInfo, CMapMetadata, DscHeader};
+pub use options::{Limit, ParseOptions};
+pub use parser::{parse_cmap, parse_cmap_from_reader, parse_cmap_lenient, parse_cmap_with_diagnostics, parse_cmap_with_options, parse_cmap_with_resolver, visit_cmap, visit_cmap_from_reader};
+pub use resolver::{CMapResolver, MAX_USECMAP_DEPTH};
+pub use visitor::{CMapVisitor, Metadata};
+pub use error::{CMapError, Location};
+pub use repair::{Repair, RepairKind};
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub enum WritingMode {
+    #[default]
     Horizontally,
     Vertically
 }
@@ -27,8 +53,36 @@ impl From<bool> for WritingMode {
     }
 }
 
-impl Default for WritingMode {
-    fn default() ->  WritingMode { WritingMode::Horizontally }
+/// A character code together with its length in bytes.
+///
+/// The length is part of the code's identity: `<41>` and `<0041>` are
+/// different codes, and can be mapped differently by a CMap with a mixed
+/// length codespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CharCode {
+    pub value: u32,
+    pub len: u8
+}
+
+impl CharCode {
+    pub fn new(value: u32, len: u8) -> CharCode {
+        CharCode { value, len }
+    }
+
+    /// Interprets `bytes` as a big-endian character code. Returns `None`
+    /// unless there are between 1 and 4 bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Option<CharCode> {
+        if bytes.is_empty() || bytes.len() > 4 {
+            return None;
+        }
+        Some(CharCode::new(as_code(bytes), bytes.len() as u8))
+    }
+}
+
+impl fmt::Display for CharCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<{:01$x}>", self.value, 2 * self.len as usize)
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -39,47 +93,169 @@ pub struct CodespaceRange {
 }
 
 impl CodespaceRange {
+    /// Checks whether `bytes` is a code in this range. As defined in Adobe
+    /// Tech Note 5014 every byte is checked against the corresponding bytes
+    /// of the range's bounds, so `<8140> <9ffc>` contains `<8240>` but not
+    /// `<81ff>`.
     pub fn in_range(&self, bytes: &[u8]) -> bool {
-        if bytes.len() != self.len { return false; }
-        let b = as_code(bytes);
-        self.from <= b && b <= self.to
+        bytes.len() == self.len && self.matching_prefix_len(bytes) == self.len
+    }
+
+    /// Returns how many of the leading bytes of `bytes` lie within the
+    /// bounds of this range.
+    fn matching_prefix_len(&self, bytes: &[u8]) -> usize {
+        bytes.iter().take(self.len).enumerate().take_while(|&(i, b)| {
+            let shift = 8 * (self.len - 1 - i);
+            (self.from >> shift) as u8 <= *b && *b <= (self.to >> shift) as u8
+        }).count()
     }
 }
 
+/// How a code split off by `CMap::split_code` relates to the codespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodeStatus {
+    /// The code lies within a codespace range.
+    Valid,
+    /// The leading bytes of the code match a codespace range, but the code
+    /// as a whole does not, or the input ended in the middle of the code.
+    Partial,
+    /// The code does not even partially match any codespace range.
+    Invalid,
+}
+
 #[derive(Clone, Debug)]
 pub struct CMapRange {
     from: u32,
     to: u32,
+    len: u8,
     start: u32
 }
 
 impl CMapRange {
-    pub fn mapped_value(&self, codepoint: u32) -> Option<u32> {
-        if self.from <= codepoint && codepoint <= self.to {
-            Some(self.start + (codepoint - self.from))
+    pub fn contains(&self, code: CharCode) -> bool {
+        self.len == code.len && self.from <= code.value && code.value <= self.to
+    }
+
+    pub fn mapped_value(&self, code: CharCode) -> Option<u32> {
+        if self.contains(code) {
+            Some(self.start.wrapping_add(code.value - self.from))
         } else {
             None
         }
     }
 }
 
+/// A character identifier, the index of a glyph in a CIDFont.
+pub type Cid = u32;
+
+/// The result of looking up the CID of a character code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CidLookup {
+    /// The code is mapped by a `cidchar` or `cidrange` entry.
+    Mapped(Cid),
+    /// The code is only covered by a `notdefchar` or `notdefrange` entry,
+    /// the CID is the one of the glyph to show in place of the character.
+    Notdef(Cid),
+    /// The code is not mapped at all.
+    Unmapped,
+}
+
+impl CidLookup {
+    /// Returns the CID to use for the code. As specified in Adobe Tech Note
+    /// 5014, unmapped codes use the notdef CID 0.
+    pub fn cid(self) -> Cid {
+        match self {
+            CidLookup::Mapped(cid) | CidLookup::Notdef(cid) => cid,
+            CidLookup::Unmapped => 0
+        }
+    }
+}
+
+/// A `bfrange` mapping a range of codes to consecutive Unicode strings.
+#[derive(Clone, Debug)]
+pub struct UnicodeRange {
+    from: u32,
+    to: u32,
+    len: u8,
+    start: String
+}
+
+impl UnicodeRange {
+    pub fn contains(&self, code: CharCode) -> bool {
+        self.len == code.len && self.from <= code.value && code.value <= self.to
+    }
+
+    /// Returns the string `code` maps to. Following the PDF specification
+    /// only the last character of the range's destination is incremented,
+    /// so a destination like `<D83DDE00>` continues with the next
+    /// supplementary character rather than the next code unit.
+    pub fn mapped_value(&self, code: CharCode) -> Option<String> {
+        if !self.contains(code) {
+            return None;
+        }
+        let mut unicode = self.start.clone();
+        let last = unicode.pop()?;
+        unicode.push(char::from_u32((last as u32).checked_add(code.value - self.from)?)?);
+        Some(unicode)
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct CMap {
-    pub name: String,
-    pub version: String,
-    pub cmap_type: i64,
-    pub writing_mode: WritingMode,
-    pub registry: String,
-    pub ordering: String,
-    pub supplement: u32,
+    /// Everything the file says about the CMap besides its mappings
+    pub metadata: CMapMetadata,
     codespace_ranges: Vec<CodespaceRange>,
-    unicode_mapping: HashMap<u32, String>,
-    unicode_range_mapping: Vec<CMapRange>,
-    cid_mapping: HashMap<u32, u32>,
-    cid_range_mapping: Vec<CMapRange>
+    unicode_mapping: HashMap<CharCode, String>,
+    unicode_range_mapping: Vec<UnicodeRange>,
+    cid_mapping: HashMap<CharCode, u32>,
+    cid_range_mapping: Vec<CMapRange>,
+    notdef_mapping: HashMap<CharCode, u32>,
+    notdef_range_mapping: Vec<CMapRange>,
+    // Built by `freeze`, `None` while ranges are still being added
+    unicode_range_index: Option<RangeIndex>,
+    cid_range_index: Option<RangeIndex>,
+    notdef_range_index: Option<RangeIndex>
 }
 
 impl CMap {
+    /// The `/CMapName`, or an empty string if it isn't defined
+    pub fn name(&self) -> &str {
+        self.metadata.name.as_ref().map_or("", |name| &name[..])
+    }
+
+    /// The `/CMapVersion` as written in the file, or an empty string if it
+    /// isn't defined. `metadata.version` holds its value.
+    pub fn version(&self) -> &str {
+        self.metadata.version_text.as_ref().map_or("", |version| &version[..])
+    }
+
+    /// The `/CMapType`, or 0 if it isn't defined
+    pub fn cmap_type(&self) -> i64 {
+        self.metadata.cmap_type.unwrap_or(0)
+    }
+
+    pub fn writing_mode(&self) -> WritingMode {
+        self.metadata.writing_mode
+    }
+
+    /// The `/Registry` of the `/CIDSystemInfo`, or an empty string
+    pub fn registry(&self) -> &str {
+        self.metadata.cid_system_info.as_ref().map_or("", |info| &info.registry[..])
+    }
+
+    /// The `/Ordering` of the `/CIDSystemInfo`, or an empty string
+    pub fn ordering(&self) -> &str {
+        self.metadata.cid_system_info.as_ref().map_or("", |info| &info.ordering[..])
+    }
+
+    /// The `/Supplement` of the `/CIDSystemInfo`, or 0
+    pub fn supplement(&self) -> u32 {
+        self.metadata.cid_system_info.as_ref().map_or(0, |info| info.supplement)
+    }
+
+    /// Returns the index of the last byte of the code at the start of
+    /// `codepoints`, or `None` if it does not start with a valid code. See
+    /// `split_code` for a variant that can always make progress.
     pub fn extract_codepoint(&self, codepoints: &[u8]) -> Option<usize> {
         let max_len = self.max_len_codespace();
         for i in 0..min(max_len+1, codepoints.len()) {
@@ -93,56 +269,179 @@ impl CMap {
         None
     }
 
-    pub fn codepoint_to_cid(&self, codepoint: u32) -> u32 {
-        if let Some(cid) = self.cid_mapping.get(&codepoint) {
-            return *cid;
+    /// Splits off the code at the start of `bytes` as described in section
+    /// 9.7.6.3 of the PDF specification. Returns `None` only if `bytes` is
+    /// empty, otherwise the code consumes at least one byte:
+    ///
+    /// * If the leading bytes form a code within a codespace range, that
+    ///   code is returned as `CodeStatus::Valid`.
+    /// * Otherwise, if the leading bytes match the start of a codespace
+    ///   range, as many bytes as that range's codes have are consumed and
+    ///   returned as `CodeStatus::Partial`. The range matching the most
+    ///   leading bytes is used, preferring shorter codes.
+    /// * Otherwise as many bytes as the shortest codes in the codespace
+    ///   have are consumed and returned as `CodeStatus::Invalid`.
+    ///
+    /// Codes are cut short when `bytes` ends before them.
+    pub fn split_code(&self, bytes: &[u8]) -> Option<(CharCode, CodeStatus)> {
+        if bytes.is_empty() {
+            return None;
         }
-        for range in &self.cid_range_mapping {
-            if let Some(cid) = range.mapped_value(codepoint) {
-                return cid;
+
+        for len in 1..min(bytes.len(), 4) + 1 {
+            if self.codespace_ranges.iter().any(|r| r.in_range(&bytes[..len])) {
+                return Some((CharCode::new(as_code(&bytes[..len]), len as u8), CodeStatus::Valid));
             }
         }
-        0 // If no mapping is found we have to return 0
+
+        let partial_match = self.codespace_ranges.iter()
+            .map(|r| (r.matching_prefix_len(bytes), r.len))
+            .filter(|&(matched, _)| matched > 0)
+            .max_by_key(|&(matched, len)| (matched, Reverse(len)));
+        let (len, status) = match partial_match {
+            Some((_, len)) => (len, CodeStatus::Partial),
+            None => (self.min_len_codespace(), CodeStatus::Invalid)
+        };
+
+        let len = min(min(len, bytes.len()), 4);
+        Some((CharCode::new(as_code(&bytes[..len]), len as u8), status))
+    }
+
+    /// Returns the CID for `code`, falling back to its notdef mapping and
+    /// finally to CID 0. Use `lookup_cid` to tell these cases apart.
+    pub fn codepoint_to_cid(&self, code: CharCode) -> Cid {
+        self.lookup_cid(code).cid()
     }
 
-    pub fn codepoint_to_unicode(&self, codepoint: u32) -> Result<String> {
-        if let Some(unicode) = self.unicode_mapping.get(&codepoint) {
-            return Ok(unicode.to_owned());
+    /// Looks up the CID for `code`. Codes without a CID mapping fall back
+    /// to their notdef mapping, if there is one.
+    pub fn lookup_cid(&self, code: CharCode) -> CidLookup {
+        if let Some(cid) = self.cid_mapping.get(&code) {
+            return CidLookup::Mapped(*cid);
         }
-        for range in &self.unicode_range_mapping {
-            if let Some(unicode) = range.mapped_value(codepoint) {
-                return as_string(&from_code(unicode));
-            }
+        if let Some(range) = find_range(&self.cid_range_mapping, &self.cid_range_index, code) {
+            return CidLookup::Mapped(range.start.wrapping_add(code.value - range.from));
+        }
+        if let Some(cid) = self.notdef_mapping.get(&code) {
+            return CidLookup::Notdef(*cid);
+        }
+        // Unlike CID ranges, every code of a notdef range maps to the same CID
+        match find_range(&self.notdef_range_mapping, &self.notdef_range_index, code) {
+            Some(range) => CidLookup::Notdef(range.start),
+            None => CidLookup::Unmapped
+        }
+    }
+
+    /// Returns the text `code` maps to. A missing mapping is reported as
+    /// `CMapError::UnmappedCode`.
+    pub fn codepoint_to_unicode(&self, code: CharCode) -> Result<String> {
+        let mut unicode = String::new();
+        if self.push_unicode(code, &mut unicode) {
+            Ok(unicode)
+        } else {
+            Err(CMapError::UnmappedCode(code))
+        }
+    }
+
+    /// Appends the text `code` maps to to `text`. Returns whether a
+    /// mapping was found.
+    fn push_unicode(&self, code: CharCode, text: &mut String) -> bool {
+        if let Some(unicode) = self.unicode_mapping.get(&code) {
+            text.push_str(unicode);
+            return true;
+        }
+        match find_range(&self.unicode_range_mapping, &self.unicode_range_index, code).and_then(|r| r.mapped_value(code)) {
+            Some(unicode) => { text.push_str(&unicode); true },
+            None => false
         }
-        Err(CMapError::NoUnicodeMappingFound(codepoint))
     }
 
     pub fn add_codespace_range(&mut self, range: CodespaceRange) {
         self.codespace_ranges.push(range);
     }
 
-    pub fn add_unicode_mapping(&mut self, codepoints: &[u8], unicode: String) {
-        self.unicode_mapping.insert(as_code(codepoints), unicode);
+    pub fn add_unicode_mapping(&mut self, code: CharCode, unicode: String) {
+        self.unicode_mapping.insert(code, unicode);
     }
 
-    fn add_unicode_range(&mut self, range: CMapRange) {
+    fn add_unicode_range(&mut self, range: UnicodeRange) {
         self.unicode_range_mapping.push(range);
+        self.unicode_range_index = None;
     }
 
-    pub fn add_cid_mapping(&mut self, codepoints: &[u8], cid: u32) {
-        self.cid_mapping.insert(as_code(codepoints), cid);
+    pub fn add_cid_mapping(&mut self, code: CharCode, cid: u32) {
+        self.cid_mapping.insert(code, cid);
     }
 
     fn add_cid_range(&mut self, range: CMapRange) {
         self.cid_range_mapping.push(range);
+        self.cid_range_index = None;
     }
 
+    pub fn add_notdef_mapping(&mut self, code: CharCode, cid: u32) {
+        self.notdef_mapping.insert(code, cid);
+    }
+
+    fn add_notdef_range(&mut self, range: CMapRange) {
+        self.notdef_range_mapping.push(range);
+        self.notdef_range_index = None;
+    }
+
+    /// Indexes the ranges of this CMap, so that looking up a code takes a
+    /// binary search instead of a scan over all ranges. Lookups give the
+    /// same results either way: where ranges overlap, the one added first
+    /// wins.
+    ///
+    /// CMaps returned by the parser are already frozen, but `merge` undoes
+    /// this, after which `freeze` should be called again.
+    pub fn freeze(&mut self) {
+        self.unicode_range_index = Some(RangeIndex::new(&self.unicode_range_mapping));
+        self.cid_range_index = Some(RangeIndex::new(&self.cid_range_mapping));
+        self.notdef_range_index = Some(RangeIndex::new(&self.notdef_range_mapping));
+    }
+
+    fn unfreeze(&mut self) {
+        self.unicode_range_index = None;
+        self.cid_range_index = None;
+        self.notdef_range_index = None;
+    }
 
     pub fn merge(&mut self, other: &CMap) {
         self.codespace_ranges.extend_from_slice(&other.codespace_ranges);
-        self.unicode_mapping.extend(other.unicode_mapping.iter().map(|(k,v)| (k.clone(), v.clone())));
+        self.unicode_mapping.extend(other.unicode_mapping.iter().map(|(k,v)| (*k, v.clone())));
+        self.unicode_range_mapping.extend_from_slice(&other.unicode_range_mapping);
         self.cid_mapping.extend(other.cid_mapping.iter());
         self.cid_range_mapping.extend_from_slice(&other.cid_range_mapping);
+        self.notdef_mapping.extend(other.notdef_mapping.iter());
+        self.notdef_range_mapping.extend_from_slice(&other.notdef_range_mapping);
+        self.unfreeze();
+    }
+
+    /// Merges `parent` underneath this CMap, as done for `usecmap`. A code
+    /// mapped by this CMap, either on its own or through a range, keeps that
+    /// mapping; everything else is taken from `parent`.
+    fn inherit(&mut self, parent: &CMap) {
+        self.unfreeze();
+        self.codespace_ranges.extend_from_slice(&parent.codespace_ranges);
+
+        for (code, unicode) in &parent.unicode_mapping {
+            let overridden = self.unicode_mapping.contains_key(code) ||
+                self.unicode_range_mapping.iter().any(|r| r.contains(*code));
+            if !overridden {
+                self.unicode_mapping.insert(*code, unicode.clone());
+            }
+        }
+        self.unicode_range_mapping.extend_from_slice(&parent.unicode_range_mapping);
+
+        // CIDs are looked up before notdef mappings, so the parent's CIDs
+        // have to leave out the codes this CMap maps to notdef
+        let notdef_codes: Vec<(u8, u32, u32)> = self.notdef_mapping.keys().map(|code| (code.len, code.value, code.value))
+            .chain(self.notdef_range_mapping.iter().map(|r| (r.len, r.from, r.to)))
+            .collect();
+        inherit_mappings(&mut self.cid_mapping, &mut self.cid_range_mapping,
+                         &parent.cid_mapping, &parent.cid_range_mapping, &notdef_codes);
+        inherit_mappings(&mut self.notdef_mapping, &mut self.notdef_range_mapping,
+                         &parent.notdef_mapping, &parent.notdef_range_mapping, &[]);
     }
 
     fn max_len_codespace(&self) -> usize {
@@ -150,6 +449,67 @@ impl CMap {
         max_len.map(|r| r.len).unwrap_or(1)
     }
 
+    fn min_len_codespace(&self) -> usize {
+        let min_len = self.codespace_ranges.iter().min_by_key(|r| r.len);
+        min_len.map(|r| r.len).unwrap_or(1)
+    }
+
+}
+
+/// Returns the first of `ranges` containing `code`, using `index` if the
+/// ranges have been indexed.
+fn find_range<'a, R: index::Range>(ranges: &'a [R], index: &Option<RangeIndex>, code: CharCode) -> Option<&'a R> {
+    match *index {
+        Some(ref index) => index.find(code.len, code.value).map(|i| &ranges[i]),
+        None => ranges.iter().find(|range| {
+            let (len, from, to) = range.bounds();
+            len == code.len && from <= code.value && code.value <= to
+        })
+    }
+}
+
+/// Adds the mappings of a parent CMap that aren't overridden, leaving out
+/// the `hidden` codes, given as their length and first and last value.
+fn inherit_mappings(mapping: &mut HashMap<CharCode, u32>, ranges: &mut Vec<CMapRange>,
+                    parent_mapping: &HashMap<CharCode, u32>, parent_ranges: &[CMapRange],
+                    hidden: &[(u8, u32, u32)]) {
+    for (code, value) in parent_mapping {
+        let overridden = mapping.contains_key(code) || ranges.iter().any(|r| r.contains(*code)) ||
+            hidden.iter().any(|&(len, from, to)| len == code.len && from <= code.value && code.value <= to);
+        if !overridden {
+            mapping.insert(*code, *value);
+        }
+    }
+    for range in parent_ranges {
+        push_uncovered(ranges, range, hidden);
+    }
+}
+
+/// Pushes the parts of `range` that don't contain any of the `hidden` codes.
+fn push_uncovered(ranges: &mut Vec<CMapRange>, range: &CMapRange, hidden: &[(u8, u32, u32)]) {
+    let mut holes: Vec<(u32, u32)> = hidden.iter()
+        .filter(|&&(len, from, to)| len == range.len && from <= range.to && to >= range.from)
+        .map(|&(_, from, to)| (from, to))
+        .collect();
+    holes.sort();
+    // The first code not covered by a hole or pushed yet, which can be past
+    // the last code of a length
+    let mut next = range.from as u64;
+    let mut push_part = |from: u64, to: u64| ranges.push(CMapRange {
+        from: from as u32,
+        to: to as u32,
+        len: range.len,
+        start: range.start.wrapping_add(from as u32 - range.from)
+    });
+    for (from, to) in holes {
+        if from as u64 > next {
+            push_part(next, from as u64 - 1);
+        }
+        next = max(next, to as u64 + 1);
+    }
+    if next <= range.to as u64 {
+        push_part(next, range.to as u64);
+    }
 }
 
 fn as_code(str: &[u8]) -> u32 {
@@ -160,55 +520,32 @@ fn as_code(str: &[u8]) -> u32 {
     code
 }
 
-fn from_code(code: u32) -> [u8; 4]{
-    let mut str = [0u8; 4];
-    for (i, byte) in str.iter_mut().enumerate() {
-        *byte = ((code >> (i*8)) & 0xFF) as u8;
-    }
-    str
-}
-
-fn as_string(str: &[u8]) -> Result<String> {
-    match str.len() {
-        0 => Ok(String::new()),
-        1 => {
-          // Luckily ISO 8859 maps 1:1 to unicode points
-            Ok((str[0] as char).to_string())
-        },
-        _ => {
-            let utf16: Result<Vec<String>> = str.chunks(2).map(|x| {
-                if x.len() == 2 {
-                    let code = x[0] as u16 | (x[1] as u16) << 8;
-                    String::from_utf16(&[code]).map_err(CMapError::Utf16)
-                } else {
-                    Ok((x[0] as char).to_string())
-                }
-            }).collect();
-            utf16.map(|v| v.concat())
-        }
+/// Decodes the destination of a `bfchar` or `bfrange` mapping, which is
+/// UTF-16BE and may hold several characters, e.g. for ligatures. Returns
+/// `None` if it isn't valid UTF-16, e.g. because of a lone surrogate.
+fn utf16be_to_string(str: &[u8]) -> Option<String> {
+    if str.len() == 1 {
+        // Not valid UTF-16, but some producers write single byte destinations.
+        // Luckily ISO 8859 maps 1:1 to unicode points
+        return Some((str[0] as char).to_string());
     }
-}
-
-fn increment_code(str: &mut [u8]) {
-    let mut carry_bit = 1;
-    for x in str.iter_mut().rev() {
-        if carry_bit > 0 && *x == 255 {
-            *x = 0;
-        }
-        else if carry_bit > 0 {
-            *x += 1;
-            carry_bit = 0;
+    let code_units: Vec<u16> = str.chunks(2).map(|x| {
+        if x.len() == 2 {
+            (x[0] as u16) << 8 | x[1] as u16
+        } else {
+            x[0] as u16
         }
-    }
+    }).collect();
+    String::from_utf16(&code_units).ok()
 }
 
 
 #[cfg(test)]
 mod tests {
     use std::fs::File;
-    use std::io::BufReader;
     use std::io::Read;
     use super::*;
+    use metadata::Value;
     /*fn do_parse(input: &[u8]) {
         let result = parse(input);
         if let Ok(lines) = result  {
@@ -228,4 +565,525 @@ mod tests {
         let cmap = parse_cmap(&contents).unwrap();
         println!("{:?}", cmap);
     }
+
+    fn read_adobe_cmap(name: &str) -> Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        File::open(format!("assets/adobe_cmaps/{}", name))
+            .and_then(|mut f| f.read_to_end(&mut contents))
+            .map_err(CMapError::Io)?;
+        Ok(contents)
+    }
+
+    #[test]
+    fn usecmap() {
+        let contents = read_adobe_cmap("90ms-RKSJ-V").unwrap();
+        let cmap = parse_cmap_with_resolver(&contents, &read_adobe_cmap).unwrap();
+        // Vertical form from 90ms-RKSJ-V overrides the range from 90ms-RKSJ-H
+        assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x8141, 2)), 7887);
+        assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x8140, 2)), 633);
+        assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x8260, 2)), 790);
+    }
+
+    #[test]
+    fn notdef() {
+        let contents = read_adobe_cmap("83pv-RKSJ-H").unwrap();
+        let cmap = parse_cmap(&contents).unwrap();
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x20, 1)), CidLookup::Mapped(1));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x05, 1)), CidLookup::Notdef(1));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x81, 1)), CidLookup::Unmapped);
+        assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x81, 1)), 0);
+    }
+
+    #[test]
+    fn code_length() {
+        let cmap = parse_cmap(b"
+            2 begincidchar
+            <41> 34
+            <0041> 1000
+            endcidchar
+            1 begincidrange
+            <8140> <817e> 633
+            endcidrange").unwrap();
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x41, 1)), CidLookup::Mapped(34));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x41, 2)), CidLookup::Mapped(1000));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x8140, 2)), CidLookup::Mapped(633));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x8140, 3)), CidLookup::Unmapped);
+        assert_eq!(CharCode::from_bytes(&[0x00, 0x41]), Some(CharCode::new(0x41, 2)));
+        assert_eq!(CharCode::new(0x41, 2).to_string(), "<0041>");
+    }
+
+    #[test]
+    fn codespace_ranges() {
+        let valid_code_len = |name: &str, bytes: &[u8]| {
+            let cmap = parse_cmap(&read_adobe_cmap(name).unwrap()).unwrap();
+            cmap.extract_codepoint(bytes).map(|i| i + 1)
+        };
+        assert_eq!(valid_code_len("83pv-RKSJ-H", &[0x41]), Some(1));
+        assert_eq!(valid_code_len("83pv-RKSJ-H", &[0x81, 0x40]), Some(2));
+        assert_eq!(valid_code_len("83pv-RKSJ-H", &[0x82, 0x9f]), Some(2));
+        assert_eq!(valid_code_len("83pv-RKSJ-H", &[0x81, 0xff]), None);
+        assert_eq!(valid_code_len("83pv-RKSJ-H", &[0xa0]), Some(1));
+        assert_eq!(valid_code_len("EUC-H", &[0x8e, 0xa0]), Some(2));
+        assert_eq!(valid_code_len("EUC-H", &[0x8e, 0xe0]), None);
+        assert_eq!(valid_code_len("EUC-H", &[0xa2, 0xa0]), None);
+        assert_eq!(valid_code_len("GBK-EUC-H", &[0x82, 0x30]), None);
+        assert_eq!(valid_code_len("GBK-EUC-H", &[0x82, 0xfe]), Some(2));
+        assert_eq!(valid_code_len("B5pc-H", &[0xa2, 0x30]), None);
+        assert_eq!(valid_code_len("B5pc-H", &[0xfc, 0xfe]), Some(2));
+        assert_eq!(valid_code_len("B5pc-H", &[0xfd]), Some(1));
+    }
+
+    #[test]
+    fn split_code() {
+        let rksj = parse_cmap(&read_adobe_cmap("83pv-RKSJ-H").unwrap()).unwrap();
+        let euc = parse_cmap(&read_adobe_cmap("EUC-H").unwrap()).unwrap();
+        assert_eq!(rksj.split_code(&[]), None);
+        assert_eq!(rksj.split_code(&[0x41, 0x81]), Some((CharCode::new(0x41, 1), CodeStatus::Valid)));
+        assert_eq!(rksj.split_code(&[0x81, 0x40]), Some((CharCode::new(0x8140, 2), CodeStatus::Valid)));
+        assert_eq!(rksj.split_code(&[0x81, 0xff, 0x41]), Some((CharCode::new(0x81ff, 2), CodeStatus::Partial)));
+        assert_eq!(rksj.split_code(&[0x81]), Some((CharCode::new(0x81, 1), CodeStatus::Partial)));
+        assert_eq!(euc.split_code(&[0x8e, 0xe0]), Some((CharCode::new(0x8ee0, 2), CodeStatus::Partial)));
+        assert_eq!(euc.split_code(&[0x90, 0xa1]), Some((CharCode::new(0x90, 1), CodeStatus::Invalid)));
+        assert_eq!(CMap::default().split_code(&[0x41, 0x42]), Some((CharCode::new(0x41, 1), CodeStatus::Invalid)));
+    }
+
+    #[test]
+    fn frozen_lookups() {
+        for name in &["UniJIS-UCS2-H", "90ms-RKSJ-H", "Adobe-Japan1-UCS2"] {
+            let frozen = parse_cmap(&read_adobe_cmap(name).unwrap()).unwrap();
+            let mut linear = CMap::default();
+            linear.merge(&frozen);
+            for value in (0..0x10000).step_by(7) {
+                let code = CharCode::new(value, 2);
+                assert_eq!(frozen.lookup_cid(code), linear.lookup_cid(code));
+                assert_eq!(frozen.codepoint_to_unicode(code).ok(), linear.codepoint_to_unicode(code).ok());
+            }
+        }
+    }
+
+    #[test]
+    fn to_unicode() {
+        let cmap = parse_cmap(b"
+            3 beginbfchar
+            <03> <0041>
+            <04> <00660069>
+            <05> <D83DDE00>
+            endbfchar
+            2 beginbfrange
+            <10> <12> <D835DCFF>
+            <20> <21> [<0042> <00e9>]
+            endbfrange").unwrap();
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x03, 1)).unwrap(), "A");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x04, 1)).unwrap(), "fi");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x05, 1)).unwrap(), "\u{1F600}");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x10, 1)).unwrap(), "\u{1D4FF}");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x12, 1)).unwrap(), "\u{1D501}");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x21, 1)).unwrap(), "\u{e9}");
+
+        let contents = read_adobe_cmap("Adobe-Japan1-UCS2").unwrap();
+        let cmap = parse_cmap(&contents).unwrap();
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x0022, 2)).unwrap(), "A");
+    }
+
+    #[test]
+    fn hex_strings() {
+        // As written by Ghostscript, with spaces between the characters of a
+        // ligature
+        let cmap = parse_cmap(b"/CIDInit /ProcSet findresource begin
+            12 dict begin
+            begincmap
+            /CIDSystemInfo
+            << /Registry (Adobe)
+            /Ordering (UCS)
+            /Supplement 0
+            >> def
+            /CMapName /Adobe-Identity-UCS def
+            /CMapType 2 def
+            1 begincodespacerange
+            <00> <FF>
+            endcodespacerange
+            3 beginbfchar
+            <01> <0054>
+            <02> <0066 0069>
+            <03> <0066 0066 006C>
+            endbfchar
+            endcmap
+            CMapName currentdict /CMap defineresource pop
+            end
+            end").unwrap();
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x01, 1)).unwrap(), "T");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x02, 1)).unwrap(), "fi");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x03, 1)).unwrap(), "ffl");
+
+        // As written by some Office exporters, with line breaks in strings
+        // and a final digit left out
+        let cmap = parse_cmap(b"1 begincodespacerange\r\n<0000> <FFFF>\r\nendcodespacerange\r\n\
+            2 beginbfchar\r\n<0003> <0020\r\n>\r\n<00\r\n11> <002E>\r\nendbfchar\r\n\
+            1 beginbfrange\r\n<0044> <0045> <041>\r\nendbfrange\r\n").unwrap();
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x03, 2)).unwrap(), " ");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x11, 2)).unwrap(), ".");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x45, 2)).unwrap(), "\u{411}");
+    }
+
+    #[test]
+    fn postscript_tokens() {
+        let cmap = parse_cmap(b"/CMapVersion 10.003 def
+            /Unused { 1 beginbfchar <01> <0041> endbfchar } bind def
+            2 beginbfchar
+            <02> <0042>
+            <03> <~!(-~>
+            endbfchar").unwrap();
+        assert_eq!(cmap.version(), "10.003");
+        for &(text, version) in &[("1.0", 1.0), ("10.10", 10.1)] {
+            let input = format!("/CMapVersion {} def", text);
+            for cmap in &[parse_cmap(input.as_bytes()).unwrap(), parse_cmap_from_reader(input.as_bytes()).unwrap()] {
+                assert_eq!((cmap.version(), cmap.metadata.version), (text, Some(version)));
+            }
+        }
+        // The number is formatted if the text found is a name
+        let named = parse_cmap(b"/V 2.50 def /CMapVersion V def").unwrap();
+        assert_eq!((named.version(), named.metadata.version), ("2.5", Some(2.5)));
+        assert!(cmap.codepoint_to_unicode(CharCode::new(0x01, 1)).is_err());
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x02, 1)).unwrap(), "B");
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x03, 1)).unwrap(), "C");
+    }
+
+    #[test]
+    fn dictionaries() {
+        // As written in Adobe's CMap resources, with definitions in a nested
+        // dictionary that don't describe the CMap
+        let cmap = parse_cmap(b"/CIDInit /ProcSet findresource begin
+            12 dict begin
+            begincmap
+            /CIDSystemInfo 3 dict dup begin
+              /Registry (Adobe) def
+              /Ordering (Japan1) def
+              /Supplement 2 def
+            end def
+            /CMapName /Test-H def
+            /Fonts 1 dict dup begin /CMapName /Other def end def
+            endcmap
+            CMapName currentdict /CMap defineresource pop
+            end
+            end").unwrap();
+        assert_eq!((cmap.registry(), cmap.ordering(), cmap.supplement()), ("Adobe", "Japan1", 2));
+        assert_eq!(cmap.name(), "Test-H");
+
+        let cmap = parse_cmap(b"/CIDSystemInfo << /Supplement 0 /Registry (Adobe) /Ordering (UCS) >> def").unwrap();
+        assert_eq!((cmap.registry(), cmap.ordering(), cmap.supplement()), ("Adobe", "UCS", 0));
+
+        let cmap = parse_cmap(&read_adobe_cmap("90ms-RKSJ-V").unwrap()).unwrap();
+        assert_eq!((cmap.registry(), cmap.ordering(), cmap.supplement()), ("Adobe", "Japan1", 2));
+
+        let error = parse_cmap(b"/Key\n1 ] def").unwrap_err();
+        assert_eq!(error.to_string(), "Encountered ] without a matching [ at line 2, column 3: `] def`");
+    }
+
+    #[test]
+    fn metadata() {
+        let contents = read_adobe_cmap("83pv-RKSJ-H").unwrap();
+        let expected = CMapMetadata {
+            name: Some("83pv-RKSJ-H".to_owned()),
+            version: Some(10.003),
+            version_text: Some("10.003".to_owned()),
+            cmap_type: Some(1),
+            writing_mode: WritingMode::Horizontally,
+            cid_system_info: Some(CidSystemInfo { registry: "Adobe".to_owned(), ordering: "Japan1".to_owned(), supplement: 1 }),
+            uid_offset: Some(0),
+            xuid: Some(vec![1, 10, 25324]),
+            cid_count: None,
+            dsc: DscHeader {
+                title: Some("83pv-RKSJ-H Adobe Japan1 1".to_owned()),
+                version: Some(10.003),
+                resource: Some(("CMap".to_owned(), "83pv-RKSJ-H".to_owned()))
+            },
+            extra: HashMap::new()
+        };
+        assert_eq!(parse_cmap(&contents).unwrap().metadata, expected);
+        assert_eq!(parse_cmap_from_reader(&contents[..]).unwrap().metadata, expected);
+
+        let cmap = parse_cmap(&read_adobe_cmap("Adobe-Japan1-6").unwrap()).unwrap();
+        assert_eq!(cmap.metadata.cid_count, Some(23058));
+
+        let cmap = parse_cmap(b"/CMapName /Test def
+            /Producer (Test) def
+            /Sizes [1 2.5 [3] << >>] def").unwrap();
+        assert_eq!(cmap.metadata.dsc, DscHeader::default());
+        assert_eq!(cmap.metadata.extra.get("Producer"), Some(&Value::String(b"Test".to_vec())));
+        assert_eq!(cmap.metadata.extra.get("Sizes"), Some(&Value::Array(vec![
+            Value::Integer(1), Value::Number(2.5), Value::Other("Array"), Value::Other("Dictionary")
+        ])));
+        assert!(!cmap.metadata.extra.contains_key("CMapName"));
+    }
+
+    #[test]
+    fn from_reader() {
+        // Reads a few bytes at a time, so that tokens span reads
+        struct ChunkedReader<'a>(&'a [u8]);
+        impl<'a> Read for ChunkedReader<'a> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = min(min(buf.len(), 3), self.0.len());
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        for name in &["83pv-RKSJ-H", "Adobe-Japan1-UCS2"] {
+            let contents = read_adobe_cmap(name).unwrap();
+            let expected = parse_cmap(&contents).unwrap();
+            let cmap = parse_cmap_from_reader(ChunkedReader(&contents)).unwrap();
+            assert_eq!(cmap.name(), expected.name());
+            assert_eq!(cmap.ordering(), expected.ordering());
+            assert_eq!(cmap.metadata, expected.metadata);
+            for value in (0..0x10000).step_by(7) {
+                let code = CharCode::new(value, 2);
+                assert_eq!(cmap.lookup_cid(code), expected.lookup_cid(code));
+                assert_eq!(cmap.codepoint_to_unicode(code).ok(), expected.codepoint_to_unicode(code).ok());
+            }
+        }
+    }
+
+    #[test]
+    fn from_reader_io_error() {
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(std::io::ErrorKind::Other, "broken stream"))
+            }
+        }
+
+        match parse_cmap_from_reader(b"1 begincidchar <00> 1".chain(FailingReader)) {
+            Err(CMapError::Io(ref e)) => assert_eq!(e.to_string(), "broken stream"),
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
+
+    #[test]
+    fn visitor() {
+        #[derive(Default)]
+        struct Events(Vec<String>);
+        impl CMapVisitor for Events {
+            fn codespace_range(&mut self, low: CharCode, high: CharCode) -> Result<()> {
+                self.0.push(format!("codespace {} {}", low, high));
+                Ok(())
+            }
+            fn cid_range(&mut self, low: CharCode, high: CharCode, cid: Cid) -> Result<()> {
+                self.0.push(format!("cidrange {} {} {}", low, high, cid));
+                Ok(())
+            }
+            fn bf_char(&mut self, code: CharCode, unicode: String) -> Result<()> {
+                self.0.push(format!("bfchar {} {}", code, unicode));
+                Ok(())
+            }
+            fn usecmap(&mut self, name: &str) -> Result<()> {
+                self.0.push(format!("usecmap {}", name));
+                Ok(())
+            }
+            fn metadata(&mut self, metadata: Metadata) -> Result<()> {
+                self.0.push(format!("{:?}", metadata));
+                Ok(())
+            }
+        }
+
+        let mut events = Events::default();
+        visit_cmap(b"
+            /CMapName /Test def
+            /H usecmap
+            1 begincodespacerange <00> <ff> endcodespacerange
+            1 begincidrange <20> <7e> 1 endcidrange
+            1 beginbfrange <41> <42> [<0061> <0062>] endbfrange", &mut events).unwrap();
+        assert_eq!(events.0, vec![
+            "Name(\"Test\")", "usecmap H", "codespace <00> <ff>",
+            "cidrange <20> <7e> 1", "bfchar <41> a", "bfchar <42> b"
+        ]);
+    }
+
+    // Inputs that used to panic, found by the targets in fuzz/
+    #[test]
+    fn fuzz_regressions() {
+        for entry in std::fs::read_dir("fuzz/regressions").unwrap() {
+            let mut contents = Vec::new();
+            File::open(entry.unwrap().path()).unwrap().read_to_end(&mut contents).unwrap();
+            if let Ok(cmap) = parse_cmap(&contents) {
+                cmap.decode_to_cids(&contents);
+                cmap.decode_to_string_lossy(&contents);
+            }
+            let _ = parse_cmap_from_reader(&contents[..]);
+            parse_cmap_lenient(&contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn error_locations() {
+        let error = parse_cmap(b"/CMapName /Test def\n2 begincidchar\n<20> 1\n<21> (2)\nendcidchar").unwrap_err();
+        assert_eq!(error.location(), Some(&Location { offset: 47, line: 4, column: 6, snippet: "(2)".to_owned() }));
+        assert_eq!(error.to_string(), "Encountered the type LiteralString, but expected Integer at line 4, column 6: `(2)`");
+
+        let error = parse_cmap_from_reader(&b"1 beginbfrange\n<00> <01> [<0041>]\n"[..]).unwrap_err();
+        assert_eq!(error.to_string(), "Encountered an array of size 1, but expected 2 at line 2, column 11: `[<0041>]`");
+
+        let error = parse_cmap(b"beginbfchar").unwrap_err();
+        assert_eq!(error.to_string(), "The operator beginbfchar is missing its operand at line 1, column 1: `beginbfchar`");
+
+        let error = parse_cmap(b"1 beginbfchar\n<01>").unwrap_err();
+        assert_eq!(error.to_string(), "The input ended in the middle of the CMap at line 2, column 5");
+
+        let error = parse_cmap(b"/CMapName /Test def\n-1\nbegincidchar <00> 1 endcidchar").unwrap_err();
+        assert_eq!(error.to_string(), "Encountered the type negative Integer, but expected non-negative Integer at line 2, column 1: `-1`");
+    }
+
+    #[test]
+    fn lenient() {
+        let input = b"1 begincodespacerange\n<00> <FF>\nendcodespacerange\n\
+            2 beginbfchar\n<01> <0041>\n<02> <0042>\n<03> <0043>\nendbfchar\n\
+            1 beginbfrange\n<10> <11> [<0061>]\n<20> <21> <0078>\n\
+            2 begincidchar\n<30> 5 7 <31> (x) <32> 6\nendcidchar\n\
+            1 beginbfchar\n<40> <0044>\n<41> (unterminated";
+        assert!(parse_cmap(input).is_err());
+
+        let (cmap, repairs) = parse_cmap_lenient(input).unwrap();
+        for &(code, unicode) in &[(0x01, "A"), (0x03, "C"), (0x20, "x"), (0x21, "y"), (0x40, "D")] {
+            assert_eq!(cmap.codepoint_to_unicode(CharCode::new(code, 1)).unwrap(), unicode);
+        }
+        let error = cmap.codepoint_to_unicode(CharCode::new(0x10, 1)).unwrap_err();
+        assert_eq!(error.to_string(), format!("No unicode mapping found for codepoint {}", CharCode::new(0x10, 1)));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x30, 1)), CidLookup::Mapped(5));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x31, 1)), CidLookup::Unmapped);
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x32, 1)), CidLookup::Mapped(6));
+
+        let repairs: Vec<_> = repairs.iter().map(|r| (r.kind, &r.message[..], r.location.line)).collect();
+        assert_eq!(repairs, [
+            (RepairKind::EntryCount, "The bfchar block declares 2 entries, but has 3", 4),
+            (RepairKind::SkippedEntry, "Encountered an array of size 1, but expected 2", 10),
+            (RepairKind::MissingEnd, "The bfrange block isn't closed", 12),
+            (RepairKind::EntryCount, "The bfrange block declares 1 entries, but has 2", 9),
+            (RepairKind::SkippedToken, "Skipped a stray Integer", 13),
+            (RepairKind::SkippedEntry, "Skipped an incomplete entry", 13),
+            (RepairKind::SkippedEntry, "Skipped an incomplete entry", 13),
+            (RepairKind::Truncated, "Unterminated literal string", 17),
+        ]);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let (cmap, diagnostics) = parse_cmap_with_diagnostics(b"/CIDInit /ProcSet findresource begin\n\
+            12 dict begin\nbegincmap\n\
+            /CMapName /Test def\n/WMode (1) def\n/XUID [1 10 25388] def\n/Producer (Test) def /CIDSystemInfo << /Registry (A) /Ordering (B) /Supplement 0 /Foo 1 >> def\n\
+            /Base usecmap\n\
+            1 beginbfchar\n<01> <0041>\nendbfchar\n\
+            showpage\n\
+            endcmap\nCMapName currentdict /CMap defineresource pop\nend\nend").unwrap();
+        assert_eq!(cmap.name(), "Test");
+        assert_eq!(cmap.metadata.cid_system_info, Some(CidSystemInfo { registry: "A".to_owned(), ordering: "B".to_owned(), supplement: 0 }));
+        assert_eq!(cmap.codepoint_to_unicode(CharCode::new(0x01, 1)).unwrap(), "A");
+
+        let diagnostics: Vec<_> = diagnostics.iter().map(|d| (d.kind, &d.message[..], d.location.line)).collect();
+        assert_eq!(diagnostics, [
+            (DiagnosticKind::UnexpectedType, "The value of /WMode has the type LiteralString, but expected Integer", 5),
+            (DiagnosticKind::UnknownKey, "The key /Producer is unknown", 7),
+            (DiagnosticKind::UnknownKey, "The key /Foo of /CIDSystemInfo is unknown", 7),
+            (DiagnosticKind::IgnoredUseCMap, "The CMap Base used with usecmap was ignored", 8),
+            (DiagnosticKind::UnknownOperator, "The operator showpage is unknown", 12),
+        ]);
+
+        for name in &["UniCNS-UCS2-H", "90ms-RKSJ-H", "Adobe-Japan1-UCS2"] {
+            let contents = read_adobe_cmap(name).unwrap();
+            let (_, diagnostics) = parse_cmap_with_diagnostics(&contents).unwrap();
+            assert!(diagnostics.iter().all(|d| d.kind == DiagnosticKind::IgnoredUseCMap), "{:?}", diagnostics);
+        }
+    }
+
+    #[test]
+    fn invalid_utf16() {
+        let input = b"2 beginbfchar\n<01> <D800>\n<02> <0041>\nendbfchar\n\
+            2 beginbfrange\n<10> <11> <DC00>\n<20> <22> [<0061> <D83D> <0063>]\nendbfrange";
+        let (cmap, diagnostics) = parse_cmap_with_diagnostics(input).unwrap();
+        for &(code, unicode) in &[(0x01, None), (0x02, Some("A")), (0x10, None), (0x20, Some("a")), (0x21, None), (0x22, Some("c"))] {
+            assert_eq!(cmap.codepoint_to_unicode(CharCode::new(code, 1)).ok().as_deref(), unicode);
+        }
+        let diagnostics: Vec<_> = diagnostics.iter().map(|d| (d.kind, &d.message[..], d.location.line)).collect();
+        assert_eq!(diagnostics, [
+            (DiagnosticKind::InvalidUnicode, "Skipped a mapping to invalid UTF-16", 2),
+            (DiagnosticKind::InvalidUnicode, "Skipped a mapping to invalid UTF-16", 6),
+            (DiagnosticKind::InvalidUnicode, "Skipped a mapping to invalid UTF-16", 7),
+        ]);
+
+        let (_, repairs) = parse_cmap_lenient(input).unwrap();
+        let repairs: Vec<_> = repairs.iter().map(|r| (r.kind, r.location.line)).collect();
+        assert_eq!(repairs, [(RepairKind::SkippedEntry, 2), (RepairKind::SkippedEntry, 6), (RepairKind::SkippedEntry, 7)]);
+    }
+
+    #[test]
+    fn limits() {
+        fn limit(input: &[u8], options: ParseOptions) -> (Limit, usize, usize) {
+            match parse_cmap_with_options(input, &options) {
+                Err(CMapError::LimitExceeded { limit, max, location }) => (limit, max, location.line),
+                r => panic!("unexpected result {:?}", r)
+            }
+        }
+        let options = ParseOptions::default();
+
+        let contents = read_adobe_cmap("UniCNS-UCS2-H").unwrap();
+        let cmap = parse_cmap_with_options(&contents, &options).unwrap();
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x4E00, 2)), parse_cmap(&contents).unwrap().lookup_cid(CharCode::new(0x4E00, 2)));
+
+        let input = b"1 beginbfchar\n<01> <0041>\nendbfchar";
+        assert_eq!(limit(input, ParseOptions { max_input_size: 20, ..options }), (Limit::InputSize, 20, 2));
+        assert_eq!(limit(b"\n2147483647 beginbfchar", options), (Limit::Mappings, 1_000_000, 2));
+        assert_eq!(limit(b"1 beginbfrange\n<00> <02> [<0041> <0042> <0043>]\nendbfrange",
+                         ParseOptions { max_mappings: 2, ..options }), (Limit::Mappings, 2, 2));
+        assert_eq!(limit(b"2 begincidchar\n<00> 1\n<01> 2\nendcidchar\n1 begincidrange\n<02> <10> 3\nendcidrange",
+                         ParseOptions { max_mappings: 2, ..options }), (Limit::Mappings, 2, 5));
+        assert_eq!(limit(b"1 begincidrange\n<00000000> <FFFFFFFF> 0\nendcidrange", options), (Limit::RangeWidth, 0x10000, 2));
+        assert_eq!(limit(b"/Key\n[[[[[ ]]]]] def", ParseOptions { max_nesting_depth: 4, ..options }), (Limit::NestingDepth, 4, 2));
+        assert_eq!(limit(b"/Key\n(((((x))))) def", ParseOptions { max_nesting_depth: 4, ..options }), (Limit::NestingDepth, 4, 2));
+        assert!(parse_cmap_with_options(b"/Key (((x))) def", &ParseOptions { max_nesting_depth: 4, ..options }).is_ok());
+        let dictionaries = b"currentdict begin currentdict begin\ncurrentdict begin end end end currentdict begin";
+        assert_eq!(limit(dictionaries, ParseOptions { max_nesting_depth: 2, ..options }), (Limit::NestingDepth, 2, 2));
+        assert!(parse_cmap_with_options(dictionaries, &ParseOptions { max_nesting_depth: 3, ..options }).is_ok());
+        assert_eq!(limit("currentdict begin ".repeat(1000).as_bytes(), options), (Limit::NestingDepth, 32, 1));
+
+        let error = parse_cmap_with_options(input, &ParseOptions { max_input_size: 20, ..options }).unwrap_err();
+        assert_eq!(error.to_string(), "The input size exceeds the limit of 20 at line 2, column 7: `0041>`");
+    }
+
+    #[test]
+    fn usecmap_notdef() {
+        let resolver = |_: &str| Ok(b"1 begincidrange <00> <ff> 100 endcidrange 1 begincidchar <10> 7 endcidchar".to_vec());
+        let cmap = parse_cmap_with_resolver(b"/Parent usecmap
+            1 beginnotdefrange <00> <1f> 1 endnotdefrange
+            1 beginnotdefchar <30> 2 endnotdefchar", &resolver).unwrap();
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x05, 1)), CidLookup::Notdef(1));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x10, 1)), CidLookup::Notdef(1));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x30, 1)), CidLookup::Notdef(2));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x20, 1)), CidLookup::Mapped(132));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0x31, 1)), CidLookup::Mapped(149));
+        assert_eq!(cmap.lookup_cid(CharCode::new(0xff, 1)), CidLookup::Mapped(355));
+    }
+
+    #[test]
+    fn usecmap_cycle() {
+        let resolver = |name: &str| Ok(format!("/{} usecmap", name).into_bytes());
+        match parse_cmap_with_resolver(b"/A usecmap", &resolver) {
+            Err(CMapError::UseCMapCycle { ref name, .. }) => assert_eq!(name, "A"),
+            r => panic!("unexpected result {:?}", r)
+        }
+        // The cycle is found at the usecmap of the resolved CMap A
+        let error = parse_cmap_with_resolver(b"%!\n/CMapName /B def /A usecmap", &resolver).unwrap_err();
+        assert_eq!(error.to_string(), "The CMap A is used recursively through usecmap at line 1, column 4: `usecmap`");
+
+        let missing = |name: &str| Err(CMapError::CMapNotFound { name: name.to_owned(), location: Location::default() });
+        let error = parse_cmap_with_resolver(b"%!\n/B usecmap", &missing).unwrap_err();
+        assert_eq!(error.to_string(), "Could not find the CMap B at line 2, column 4: `usecmap`");
+    }
+
+    #[test]
+    fn usecmap_depth() {
+        let resolver = |name: &str| Ok(format!("/{}0 usecmap", name).into_bytes());
+        match parse_cmap_with_resolver(b"/A usecmap", &resolver) {
+            Err(CMapError::UseCMapDepthExceeded { max, .. }) => assert_eq!(max, MAX_USECMAP_DEPTH),
+            r => panic!("unexpected result {:?}", r)
+        }
+    }
 }
@@ -0,0 +1,131 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use ::CMap;
+use error::{Result, CMapError, Location};
+use parser::parse_cmap_with_resolver;
+use resolver::{CMapCache, CMapResolver};
+
+/// Loads CMap resources by name from a list of directories.
+///
+/// Each search path is checked for the CMap in the following places, in
+/// order:
+///
+/// * `<path>/<name>`, for a plain directory of CMaps or a directory that
+///   is already the `CMap` category of a resource tree
+/// * `<path>/CMap/<name>`, for a resource directory
+/// * `<path>/Resource/CMap/<name>`, for a Ghostscript installation
+/// * `<path>/*/CMap/<name>`, for a checkout of Adobe's cmap-resources,
+///   which keeps every character collection in its own directory such as
+///   `Adobe-Japan1-7/CMap`
+///
+/// The first search path containing the CMap wins. `usecmap` references
+/// are resolved through the same search paths, and parsed CMaps are cached
+/// by the path they were loaded from.
+pub struct CMapDirectoryLoader {
+    search_paths: Vec<PathBuf>,
+    cache: CMapCache<PathBuf>,
+}
+
+impl CMapDirectoryLoader {
+    pub fn new<I, P>(search_paths: I) -> CMapDirectoryLoader
+        where I: IntoIterator<Item = P>, P: Into<PathBuf>
+    {
+        CMapDirectoryLoader {
+            search_paths: search_paths.into_iter().map(Into::into).collect(),
+            cache: CMapCache::new(),
+        }
+    }
+
+    pub fn search_paths(&self) -> &[PathBuf] {
+        &self.search_paths
+    }
+
+    /// Returns the path of the CMap resource called `name`, if any of the
+    /// search paths contains it.
+    pub fn find(&self, name: &str) -> Option<PathBuf> {
+        // The name usually comes from a PDF, don't let it escape the search paths
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return None;
+        }
+        self.search_paths.iter().filter_map(|dir| find_in(dir, name)).next()
+    }
+
+    /// Loads and parses the CMap resource called `name`, resolving its
+    /// `usecmap` references through this loader.
+    pub fn load(&self, name: &str) -> Result<Arc<CMap>> {
+        self.load_with(name, &mut |contents| parse_cmap_with_resolver(contents, self))
+    }
+
+    fn load_with(&self, name: &str, parse: &mut dyn FnMut(&[u8]) -> Result<CMap>) -> Result<Arc<CMap>> {
+        let path = self.find(name).ok_or_else(|| CMapError::CMapNotFound { name: name.to_owned(), location: Location::default() })?;
+        self.cache.get_or_parse(path, |path| fs::read(path).map_err(CMapError::Io), parse)
+    }
+}
+
+impl CMapResolver for CMapDirectoryLoader {
+    fn resolve(&self, name: &str) -> Result<Vec<u8>> {
+        let path = self.find(name).ok_or_else(|| CMapError::CMapNotFound { name: name.to_owned(), location: Location::default() })?;
+        fs::read(path).map_err(CMapError::Io)
+    }
+
+    fn resolve_cmap(&self, name: &str, parse: &mut dyn FnMut(&[u8]) -> Result<CMap>) -> Result<Arc<CMap>> {
+        self.load_with(name, parse)
+    }
+}
+
+fn find_in(dir: &Path, name: &str) -> Option<PathBuf> {
+    let candidates = [dir.join(name), dir.join("CMap").join(name), dir.join("Resource").join("CMap").join(name)];
+    if let Some(path) = candidates.iter().find(|p| p.is_file()) {
+        return Some(path.clone());
+    }
+
+    // Should several collections contain the CMap, prefer the later
+    // supplement, independently of the order of the directory listing
+    fs::read_dir(dir).ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path().join("CMap").join(name))
+        .filter(|path| path.is_file())
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::CharCode;
+
+    #[test]
+    fn load() {
+        let loader = CMapDirectoryLoader::new(vec!["assets/adobe_cmaps"]);
+        assert!(loader.find("../Cargo.toml").is_none());
+        let error = loader.load("NotACMap").unwrap_err();
+        assert!(error.location().is_none());
+        assert_eq!(error.to_string(), "Could not find the CMap NotACMap at an unknown location");
+
+        let cmap = loader.load("90ms-RKSJ-V").unwrap();
+        assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x8141, 2)), 7887);
+        assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x8260, 2)), 790);
+        assert!(Arc::ptr_eq(&cmap, &loader.load("90ms-RKSJ-V").unwrap()));
+        // The used CMap is cached as well
+        let parent = loader.find("90ms-RKSJ-H").unwrap();
+        assert!(loader.cache.contains(&parent));
+
+        // The layouts of resource directories
+        let root = ::std::env::temp_dir().join(format!("cmap-loader-{}", ::std::process::id()));
+        for (i, dir) in ["CMap", "Resource/CMap", "Adobe-Japan1-7/CMap"].iter().enumerate() {
+            let search_path = root.join(i.to_string());
+            let dir = search_path.join(dir);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("Test-H"), "1 begincidrange <00> <ff> 100 endcidrange").unwrap();
+            fs::write(dir.join("Test-V"), "/Test-H usecmap 1 begincidchar <10> 7 endcidchar").unwrap();
+
+            let loader = CMapDirectoryLoader::new(vec![&search_path]);
+            let cmap = loader.load("Test-V").unwrap();
+            assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x10, 1)), 7);
+            assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x11, 1)), 117);
+            assert!(loader.cache.contains(&dir.join("Test-H")));
+        }
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
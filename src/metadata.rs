@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use WritingMode;
+
+/// The `/CIDSystemInfo` of a CMap, naming the character collection its
+/// CIDs belong to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CidSystemInfo {
+    /// `/Registry`, e.g. `Adobe`
+    pub registry: String,
+    /// `/Ordering`, e.g. `Japan1`
+    pub ordering: String,
+    /// `/Supplement`
+    pub supplement: u32,
+}
+
+/// The DSC comments at the start of a CMap resource file, such as
+/// `%%Title: (83pv-RKSJ-H Adobe Japan1 1)`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DscHeader {
+    /// The text of `%%Title`, without its parentheses
+    pub title: Option<String>,
+    /// The version of `%%Version`, leaving out the revision that may follow
+    pub version: Option<f64>,
+    /// The category and the name of the resource of `%%BeginResource`, e.g.
+    /// `CMap` and `83pv-RKSJ-H`
+    pub resource: Option<(String, String)>,
+}
+
+/// The value of a key that isn't part of the CMap format.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+    /// The bytes of a string
+    String(Vec<u8>),
+    Name(String),
+    /// An array of at most `MAX_ARRAY_LEN` elements. Arrays within it are
+    /// only kept as `Other`.
+    Array(Vec<Value>),
+    /// A value whose contents aren't kept, with the name of its type, e.g.
+    /// `Dictionary` or `Procedure`
+    Other(&'static str),
+}
+
+/// The longest array kept in a `Value` or as `/XUID`. Longer arrays are
+/// kept as `Value::Other` and a longer `/XUID` is ignored, so that defining
+/// the same array under many keys stays cheap.
+pub const MAX_ARRAY_LEN: usize = 256;
+
+/// Everything a CMap file says about the CMap, besides its mappings.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CMapMetadata {
+    /// `/CMapName`
+    pub name: Option<String>,
+    /// `/CMapVersion`
+    pub version: Option<f64>,
+    /// `/CMapVersion` as written in the file, e.g. `10.003`
+    pub version_text: Option<String>,
+    /// `/CMapType`
+    pub cmap_type: Option<i64>,
+    /// `/WMode`
+    pub writing_mode: WritingMode,
+    /// `/CIDSystemInfo`
+    pub cid_system_info: Option<CidSystemInfo>,
+    /// `/UIDOffset`
+    pub uid_offset: Option<i64>,
+    /// `/XUID`, the extended unique ID of the CMap
+    pub xuid: Option<Vec<i64>>,
+    /// `/CIDCount`, the number of CIDs of the collection, found in CMaps
+    /// mapping CIDs to Unicode
+    pub cid_count: Option<u32>,
+    pub dsc: DscHeader,
+    /// The keys defined in the CMap that aren't part of the CMap format
+    pub extra: HashMap<String, Value>,
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+
+/// A resource limit of `ParseOptions`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    InputSize,
+    Mappings,
+    RangeWidth,
+    NestingDepth,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Limit::InputSize => "input size",
+            Limit::Mappings => "number of mappings",
+            Limit::RangeWidth => "range width",
+            Limit::NestingDepth => "nesting depth",
+        })
+    }
+}
+
+/// Limits on the resources used by `parse_cmap_with_options`, for parsing
+/// CMaps from untrusted sources. Going over a limit fails with
+/// `CMapError::LimitExceeded`.
+///
+/// The default limits are far above what the predefined Adobe CMaps need.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// The maximum size of the input in bytes.
+    pub max_input_size: usize,
+    /// The maximum number of mappings of all blocks together, not counting
+    /// codespace ranges. A range is one mapping, but every element of the
+    /// array of a `bfrange` entry is one.
+    pub max_mappings: usize,
+    /// The maximum number of codes of a range.
+    pub max_range_width: usize,
+    /// The maximum number of arrays, dictionaries and procedures, or
+    /// parentheses in a literal string, that are open at once. It also
+    /// limits the number of dictionaries on the dictionary stack.
+    pub max_nesting_depth: usize,
+}
+
+impl ParseOptions {
+    /// Options without any limits, as used by `parse_cmap`.
+    pub fn unlimited() -> ParseOptions {
+        ParseOptions {
+            max_input_size: usize::MAX,
+            max_mappings: usize::MAX,
+            max_range_width: usize::MAX,
+            max_nesting_depth: usize::MAX,
+        }
+    }
+
+    pub(crate) fn max(&self, limit: Limit) -> usize {
+        match limit {
+            Limit::InputSize => self.max_input_size,
+            Limit::Mappings => self.max_mappings,
+            Limit::RangeWidth => self.max_range_width,
+            Limit::NestingDepth => self.max_nesting_depth,
+        }
+    }
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            max_input_size: 16 << 20,
+            max_mappings: 1_000_000,
+            max_range_width: 0x10000,
+            max_nesting_depth: 32,
+        }
+    }
+}
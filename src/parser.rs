@@ -1,200 +1,990 @@
+use std::borrow::Cow;
+use std::io::Read;
 use std::str;
+use std::sync::Arc;
 
 use ::*;
 use error::*;
-use lexer::{Value, lexer};
+use lexer::{Lexer, Position, ReaderLexer, Token, Tokens, hex_bytes, position_at};
+use options::{Limit, ParseOptions};
+use resolver::{CMapResolver, MAX_USECMAP_DEPTH};
+use diagnostic::{Diagnostic, DiagnosticKind};
+use interpreter::{Definition, Interpreter, Object};
+use metadata::{Value, MAX_ARRAY_LEN};
+use repair::{Repair, RepairKind};
+use visitor::{CMapVisitor, Metadata};
 
+/// Parses a CMap, ignoring any `usecmap` references it contains.
 pub fn parse_cmap(input: &[u8]) -> Result<CMap> {
-    let lexems = lexer(input).unwrap();
+    build(Lexer::new(input), None, &mut Vec::new())
+}
 
-    let mut cmap: CMap = Default::default();
+/// Parses a CMap while reading it from `reader`, ignoring any `usecmap`
+/// references it contains. The file is tokenized as it is read, so it is
+/// never held in memory as a whole.
+pub fn parse_cmap_from_reader<R: Read>(reader: R) -> Result<CMap> {
+    build(ReaderLexer::new(reader), None, &mut Vec::new())
+}
 
-    let mut i = 0;
-    while i < lexems.len() {
-        match lexems[i] {
-            Value::Operator(ref op) => {
-                match op.as_ref() {
-                    "beginbfchar" => {
-                        let size = lexems[i-1].as_integer()?;
-                        for k in 0..(size as usize) {
-                            let idx = i + 1 + 2*k;
-                            let char_code = lexems[idx].as_literal_string()?;
-
-                            match lexems[idx+1] {
-                                Value::LiteralString(ref output_code) => {
-                                    let unicode = str::from_utf8(output_code).map_err(CMapError::Utf8)?;
-                                    cmap.add_unicode_mapping(char_code, unicode.to_owned())
-                                },
-                                Value::Name(ref output_name) => {
-                                    let name = str::from_utf8(output_name).map_err(CMapError::Utf8)?;
-                                    cmap.add_unicode_mapping(char_code, name.to_owned());
-                                },
-                                _ => {
-                                    return lexems[idx+1].expect_type(&"Literal String or Name");
-                                }
-                            }
+/// Parses a CMap and merges the CMaps it references through `usecmap`,
+/// which are loaded from `resolver`. Mappings of the parsed CMap take
+/// precedence over the ones it inherits.
+pub fn parse_cmap_with_resolver<R: CMapResolver>(input: &[u8], resolver: &R) -> Result<CMap> {
+    build(Lexer::new(input), Some(resolver), &mut Vec::new())
+}
 
-                        }
-                        i += (1 + 2*(size + 1)) as usize;
-                    },
-                    "beginbfrange" => {
-                        let size = lexems[i-1].as_integer()?;
-                        for k in 0..(size as usize) {
-                            let idx = i + 1 + 3*k;
-                            let upper_code = lexems[idx].as_literal_string()?;
-                            let lower_code = lexems[idx+1].as_literal_string()?;
-                            match lexems[idx+2] {
-                                Value::LiteralString(ref start) => {
-                                    //let mut idx: Vec<u8> = lower_code.to_owned();
-                                    //let mut uni = start.clone();
-                                    // inclusive ranges would be nice
-                                    let range = CMapRange {
-                                        from: as_code(lower_code),
-                                        to: as_code(upper_code),
-                                        start: as_code(start)
-                                    };
-                                    cmap.add_unicode_range(range);
-                                    /*
-                                    for _ in as_code(lower_code)..as_code(upper_code)+1 {
-                                        let mut unicode = String::from_utf8(uni.to_owned())
-                                            .map_err(|e| CMapError::Utf8(e.utf8_error()))?;
-                                        cmap.add_unicode_mapping(&idx, unicode);
-
-                                        increment_code(&mut idx);
-                                        increment_code(&mut uni);
-                                    }*/
-                                }
-                                Value::Array(ref codes) => {
-                                    let expected_len = (as_code(upper_code) - as_code(lower_code) + 1) as usize;
-                                    if expected_len as usize != codes.len() {
-                                        return Err(CMapError::InvalidArrayLength { expected: expected_len as usize, found: codes.len()});
-                                    }
-                                    let mut idx = lower_code.to_owned();
-                                    for c in 0..expected_len {
-                                        let uni = codes[c as usize].as_literal_string()?;
-                                        let mut unicode = String::from_utf8(uni.to_owned())
-                                            .map_err(|e| CMapError::Utf8(e.utf8_error()))?;
+/// Parses a CMap like `parse_cmap`, failing with `CMapError::LimitExceeded`
+/// if it goes over one of the limits of `options`.
+pub fn parse_cmap_with_options(input: &[u8], options: &ParseOptions) -> Result<CMap> {
+    let mut lexer = Lexer::new(input);
+    if input.len() > options.max_input_size {
+        let error = CMapError::LimitExceeded {
+            limit: Limit::InputSize,
+            max: options.max_input_size,
+            location: position_at(input, options.max_input_size).into()
+        };
+        return Err(error.locate(Location::default(), |offset| lexer.snippet(offset)));
+    }
+    lexer.set_max_nesting_depth(options.max_nesting_depth);
 
+    let mut used = Vec::new();
+    let mut builder = CMapBuilder::new(None, &mut used);
+    {
+        let mut parser = Parser::new(lexer, &mut builder);
+        parser.options = *options;
+        parser.parse()?;
+    }
+    Ok(builder.finish())
+}
 
-                                        cmap.add_unicode_mapping(&idx, unicode);
+/// Parses a CMap, passing its mappings and metadata to `visitor` in the
+/// order they appear in the file. `usecmap` references are reported but
+/// not followed.
+pub fn visit_cmap<V: CMapVisitor>(input: &[u8], visitor: &mut V) -> Result<()> {
+    parse(Lexer::new(input), visitor)
+}
 
-                                        increment_code(&mut idx)
-                                    }
-                                }
-                                _ => { return lexems[idx+2].expect_type(&"Literal String or Array"); }
-                            }
-                        }
-                        i += (1 + 3*(size + 1)) as usize;
+/// Like `visit_cmap`, but reads the CMap from `reader` while parsing it.
+pub fn visit_cmap_from_reader<R: Read, V: CMapVisitor>(reader: R, visitor: &mut V) -> Result<()> {
+    parse(ReaderLexer::new(reader), visitor)
+}
 
-                    },
-                    "begincodespacerange" => {
-                        let size = lexems[i-1].as_integer()?;
-                        for k in 0..(size as usize) {
-                            let idx = i + 1 + 2 * k;
-                            let upper_code = lexems[idx].as_literal_string()?;
-                            let lower_code = lexems[idx+1].as_literal_string()?;
-
-                            let range = CodespaceRange {
-                                from: as_code(lower_code),
-                                to: as_code(upper_code),
-                                len: upper_code.len()
-                            };
-
-                            cmap.add_codespace_range(range);
-                        }
-                        i += (1 + 2*(size + 1)) as usize;
-                    },
-                    "begincidchar" => {
-                        let size = lexems[i-1].as_integer()?;
-                        for k in 0..(size as usize) {
-                            let idx = i + 1 + 2 * k;
-                            let char_code = lexems[idx].as_literal_string()?;
-                            let cid = lexems[idx+1].as_integer()?;
-                            cmap.add_cid_mapping(char_code, cid as u32);
-                        }
-                        i += (1 + 2*(size + 1)) as usize;
-                    },
-                    "begincidrange" => {
-                        let size = lexems[i-1].as_integer()?;
-                        for k in 0..(size as usize) {
-                            let idx = i + 1 + 3 * k;
-                            let upper_code = lexems[idx].as_literal_string()?;
-                            let lower_code = lexems[idx+1].as_literal_string()?;
-
-                            let start = lexems[idx+2].as_integer()?;
-
-                            let range = CMapRange {
-                                from: as_code(upper_code),
-                                to: as_code(lower_code),
-                                start: start as u32
-                            };
-
-                            cmap.add_cid_range(range);
-                        }
-                        i += (1 + 3*(size + 1)) as usize;
-                    },
-                    "usecmap" => {
-                        let other_cmap = lexems[i-1].as_literal_string()?;
-                        let referenced_cmap = str::from_utf8(other_cmap).map_err(CMapError::Utf8)?;
-                        i += 2;
-                    },
-                    "endcmap" => { break; },
-                    _ => {
-                        //return Err(CMapError::UnknownOperator(op.to_owned()));
-                        i += 1;
+/// Parses a CMap as far as possible, working around the mistakes found in
+/// CMaps written by PDF producers, and returns it together with what was
+/// repaired. Blocks are parsed up to their `end` operator whatever number
+/// of entries they declare, and invalid entries and stray tokens are
+/// skipped. If the input can't be tokenized to the end, e.g. because the
+/// file is truncated, the mappings up to that point are returned.
+/// Like `parse_cmap`, it ignores `usecmap` references.
+pub fn parse_cmap_lenient(input: &[u8]) -> Result<(CMap, Vec<Repair>)> {
+    let mut used = Vec::new();
+    let mut builder = CMapBuilder::new(None, &mut used);
+    let repairs = {
+        let mut parser = Parser::new(Lexer::new(input), &mut builder);
+        parser.repairs = Some(Vec::new());
+        parser.parse()?;
+        parser.repairs.unwrap_or_default()
+    };
+    Ok((builder.finish(), repairs))
+}
+
+/// Parses a CMap like `parse_cmap`, and returns it together with warnings
+/// about the contents that were ignored: unknown operators and keys, values
+/// of the wrong type and `usecmap` references.
+pub fn parse_cmap_with_diagnostics(input: &[u8]) -> Result<(CMap, Vec<Diagnostic>)> {
+    let mut used = Vec::new();
+    let mut builder = CMapBuilder::new(None, &mut used);
+    let diagnostics = {
+        let mut parser = Parser::new(Lexer::new(input), &mut builder);
+        parser.diagnostics = Some(Vec::new());
+        parser.parse()?;
+        parser.diagnostics.unwrap_or_default()
+    };
+    Ok((builder.finish(), diagnostics))
+}
+
+/// A token together with its position.
+type Located<'a> = (Token<'a>, Position);
+
+fn char_code(token: &Token) -> Result<CharCode> {
+    let (value, len) = match *token {
+        // Most codes are hex strings, which can be decoded without allocating
+        Token::HexString(ref digits) => hex_bytes(digits).fold((0, 0), |(value, len), b| (value << 8 | b as u32, len + 1)),
+        _ => {
+            let bytes = token.as_string()?;
+            (as_code(&bytes), bytes.len())
+        }
+    };
+    if len == 0 || len > 4 {
+        return Err(CMapError::InvalidCharCodeLength { len, location: Location::default() });
+    }
+    Ok(CharCode::new(value, len as u8))
+}
+
+fn to_string(bytes: Cow<[u8]>) -> Result<String> {
+    String::from_utf8(bytes.into_owned()).map_err(|e| CMapError::Utf8 { cause: e.utf8_error(), location: Location::default() })
+}
+
+fn use_cmap(name: &str, resolver: &dyn CMapResolver, used: &mut Vec<String>) -> Result<Arc<CMap>> {
+    if used.iter().any(|n| n == name) {
+        return Err(CMapError::UseCMapCycle { name: name.to_owned(), location: Location::default() });
+    }
+    if used.len() >= MAX_USECMAP_DEPTH {
+        return Err(CMapError::UseCMapDepthExceeded { name: name.to_owned(), max: MAX_USECMAP_DEPTH, location: Location::default() });
+    }
+    used.push(name.to_owned());
+    let parent = resolver.resolve_cmap(name, &mut |input| build(Lexer::new(input), Some(resolver), used));
+    used.pop();
+    parent
+}
+
+/// The keys of the `/CIDSystemInfo` dictionary.
+const CID_SYSTEM_INFO_KEYS: &[&[u8]] = &[b"Registry", b"Ordering", b"Supplement"];
+
+/// The keys defined by CMap files. Not all of them are interpreted.
+const KNOWN_KEYS: &[&[u8]] = &[
+    b"CMapName", b"CMapVersion", b"CMapType", b"CIDSystemInfo", b"Registry", b"Ordering", b"Supplement",
+    b"WMode", b"UIDOffset", b"XUID", b"CIDCount"
+];
+
+/// The operators of CMap files that are accepted but have no effect, besides
+/// the ones run by the interpreter.
+const KNOWN_OPERATORS: &[&[u8]] = &[
+    b"usefont", b"beginusematrix", b"endusematrix", b"beginrearrangedfont", b"endrearrangedfont",
+    b"endcodespacerange", b"endbfchar", b"endbfrange", b"endcidchar", b"endcidrange", b"endnotdefchar",
+    b"endnotdefrange"
+];
+
+/// Interprets `key value def`, for the entries describing the CMap. Values
+/// of the wrong type for a key result in a `CMapType` error.
+fn metadata(key: &[u8], value: &Object, interpreter: &Interpreter) -> Result<Option<Metadata>> {
+    let metadata = match (key, value) {
+        (b"WMode", &Object::Simple(Token::Integer(mode))) => Metadata::WritingMode(WritingMode::from(mode != 0)),
+        (b"CMapName", Object::Simple(name @ Token::Name(_))) => Metadata::Name(to_string(name.as_name()?)?),
+        (b"CMapVersion", Object::Simple(Token::Integer(version))) => Metadata::Version(version.to_string()),
+        (b"CMapVersion", Object::Simple(Token::Number(version))) => Metadata::Version(version.to_string()),
+        (b"CMapVersion", Object::Simple(s)) if s.is_string() => Metadata::Version(to_string(s.as_string()?)?),
+        (b"CMapType", &Object::Simple(Token::Integer(cmap_type))) => Metadata::CMapType(cmap_type),
+        (b"Registry", Object::Simple(s)) if s.is_string() => Metadata::Registry(to_string(s.as_string()?)?),
+        (b"Ordering", Object::Simple(s)) if s.is_string() => Metadata::Ordering(to_string(s.as_string()?)?),
+        (b"Supplement", &Object::Simple(Token::Integer(supplement))) => Metadata::Supplement(supplement as u32),
+        (b"UIDOffset", &Object::Simple(Token::Integer(offset))) => Metadata::UidOffset(offset),
+        (b"CIDCount", &Object::Simple(Token::Integer(count))) => Metadata::CidCount(count as u32),
+        (b"XUID", &Object::Array(index)) if interpreter.array(index).len() <= MAX_ARRAY_LEN => {
+            let xuid = interpreter.array(index).iter().map(|element| match *element {
+                Object::Simple(Token::Integer(i)) => Ok(i),
+                ref other => other.expect_type("Integer")
+            }).collect::<Result<_>>()?;
+            Metadata::Xuid(xuid)
+        },
+        (b"WMode", _) | (b"CMapType", _) | (b"Supplement", _) | (b"UIDOffset", _) | (b"CIDCount", _) => {
+            return value.expect_type("Integer")
+        },
+        (b"XUID", &Object::Array(_)) => return Ok(None),
+        (b"XUID", _) => return value.expect_type("Array"),
+        (b"CMapName", _) => return value.expect_type("Name"),
+        (b"CMapVersion", _) => return value.expect_type("Number or Literal String"),
+        (b"Registry", _) | (b"Ordering", _) => return value.expect_type("Literal String"),
+        (b"CIDSystemInfo", _) => return value.expect_type("Dictionary"),
+        _ => return Ok(None)
+    };
+    Ok(Some(metadata))
+}
+
+/// Converts the value of a key that isn't part of the CMap format, keeping
+/// the elements of arrays that aren't nested in another one.
+fn extra_value(object: &Object, interpreter: &Interpreter, nested: bool) -> Result<Value> {
+    Ok(match *object {
+        Object::Simple(Token::Integer(i)) => Value::Integer(i),
+        Object::Simple(Token::Number(n)) => Value::Number(n),
+        Object::Simple(Token::Boolean(b)) => Value::Boolean(b),
+        Object::Simple(ref s) if s.is_string() => Value::String(s.as_string()?.into_owned()),
+        Object::Simple(ref name @ Token::Name(_)) => Value::Name(String::from_utf8_lossy(&name.as_name()?).into_owned()),
+        Object::Array(index) if !nested && interpreter.array(index).len() <= MAX_ARRAY_LEN => {
+            let elements = interpreter.array(index).iter().map(|element| extra_value(element, interpreter, true));
+            Value::Array(elements.collect::<Result<_>>()?)
+        },
+        ref other => Value::Other(other.get_type())
+    })
+}
+
+/// Interprets a DSC comment like `%%Title: (Identity)`, given without its
+/// `%%`.
+fn dsc_comment(comment: &[u8]) -> Option<Metadata> {
+    let comment = String::from_utf8_lossy(comment);
+    let (keyword, value) = comment.split_once(':')?;
+    let value = value.trim();
+    // Text is usually written in parentheses, which aren't part of it
+    let text = |text: &str| text.strip_prefix('(').and_then(|t| t.strip_suffix(')')).unwrap_or(text).to_owned();
+    match keyword {
+        "Title" => Some(Metadata::Title(text(value))),
+        "Version" => value.split_whitespace().next()?.parse().ok().map(Metadata::DscVersion),
+        "BeginResource" => {
+            let (category, name) = value.split_once(char::is_whitespace)?;
+            Some(Metadata::Resource(category.to_owned(), text(name.trim())))
+        },
+        _ => None
+    }
+}
+
+/// Builds a `CMap` from the contents reported by the parser.
+struct CMapBuilder<'a> {
+    cmap: CMap,
+    parents: Vec<Arc<CMap>>,
+    resolver: Option<&'a dyn CMapResolver>,
+    // The names of the CMaps currently being parsed because of `usecmap`,
+    // outermost first
+    used: &'a mut Vec<String>
+}
+
+impl<'a> CMapBuilder<'a> {
+    fn new(resolver: Option<&'a dyn CMapResolver>, used: &'a mut Vec<String>) -> CMapBuilder<'a> {
+        CMapBuilder { cmap: Default::default(), parents: Vec::new(), resolver, used }
+    }
+
+    fn finish(self) -> CMap {
+        let CMapBuilder { mut cmap, parents, .. } = self;
+        for parent in &parents {
+            cmap.inherit(parent);
+        }
+        cmap.freeze();
+        cmap
+    }
+}
+
+impl<'a> CMapVisitor for CMapBuilder<'a> {
+    fn codespace_range(&mut self, low: CharCode, high: CharCode) -> Result<()> {
+        self.cmap.add_codespace_range(CodespaceRange { from: low.value, to: high.value, len: low.len as usize });
+        Ok(())
+    }
+
+    fn cid_char(&mut self, code: CharCode, cid: Cid) -> Result<()> {
+        self.cmap.add_cid_mapping(code, cid);
+        Ok(())
+    }
+
+    fn cid_range(&mut self, low: CharCode, high: CharCode, cid: Cid) -> Result<()> {
+        self.cmap.add_cid_range(CMapRange { from: low.value, to: high.value, len: low.len, start: cid });
+        Ok(())
+    }
+
+    fn notdef_char(&mut self, code: CharCode, cid: Cid) -> Result<()> {
+        self.cmap.add_notdef_mapping(code, cid);
+        Ok(())
+    }
+
+    fn notdef_range(&mut self, low: CharCode, high: CharCode, cid: Cid) -> Result<()> {
+        self.cmap.add_notdef_range(CMapRange { from: low.value, to: high.value, len: low.len, start: cid });
+        Ok(())
+    }
+
+    fn bf_char(&mut self, code: CharCode, unicode: String) -> Result<()> {
+        self.cmap.add_unicode_mapping(code, unicode);
+        Ok(())
+    }
+
+    fn bf_range(&mut self, low: CharCode, high: CharCode, start: String) -> Result<()> {
+        self.cmap.add_unicode_range(UnicodeRange { from: low.value, to: high.value, len: low.len, start });
+        Ok(())
+    }
+
+    fn usecmap(&mut self, name: &str) -> Result<()> {
+        if let Some(resolver) = self.resolver {
+            self.parents.push(use_cmap(name, resolver, self.used)?);
+        }
+        Ok(())
+    }
+
+    fn metadata(&mut self, metadata: Metadata) -> Result<()> {
+        let cmap = &mut self.cmap;
+        match metadata {
+            Metadata::Name(name) => cmap.metadata.name = Some(name),
+            Metadata::Version(version) => {
+                cmap.metadata.version = version.parse().ok();
+                cmap.metadata.version_text = Some(version);
+            },
+            Metadata::CMapType(cmap_type) => cmap.metadata.cmap_type = Some(cmap_type),
+            Metadata::WritingMode(writing_mode) => cmap.metadata.writing_mode = writing_mode,
+            Metadata::Registry(registry) => cmap.metadata.cid_system_info.get_or_insert_with(Default::default).registry = registry,
+            Metadata::Ordering(ordering) => cmap.metadata.cid_system_info.get_or_insert_with(Default::default).ordering = ordering,
+            Metadata::Supplement(supplement) => cmap.metadata.cid_system_info.get_or_insert_with(Default::default).supplement = supplement,
+            Metadata::UidOffset(offset) => cmap.metadata.uid_offset = Some(offset),
+            Metadata::Xuid(xuid) => cmap.metadata.xuid = Some(xuid),
+            Metadata::CidCount(count) => cmap.metadata.cid_count = Some(count),
+            Metadata::Title(title) => cmap.metadata.dsc.title = Some(title),
+            Metadata::DscVersion(version) => cmap.metadata.dsc.version = Some(version),
+            Metadata::Resource(category, name) => cmap.metadata.dsc.resource = Some((category, name)),
+            Metadata::Other(key, value) => {
+                cmap.metadata.extra.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn build<'a, T>(lexer: T, resolver: Option<&dyn CMapResolver>, used: &mut Vec<String>) -> Result<CMap>
+    where T: Tokens<'a>
+{
+    let mut builder = CMapBuilder::new(resolver, used);
+    Parser::new(lexer, &mut builder).parse()?;
+    Ok(builder.finish())
+}
+
+fn parse<'a, T, V>(lexer: T, visitor: &mut V) -> Result<()>
+    where T: Tokens<'a>, V: CMapVisitor + ?Sized
+{
+    Parser::new(lexer, visitor).parse()
+}
+
+/// The blocks of entries making up the mappings of a CMap, e.g.
+/// `1 beginbfchar <01> <0041> endbfchar`.
+#[derive(Clone, Copy, PartialEq)]
+enum Block {
+    CodespaceRange,
+    BfChar,
+    BfRange,
+    CidChar,
+    CidRange,
+    NotdefChar,
+    NotdefRange
+}
+
+impl Block {
+    fn from_begin(op: &[u8]) -> Option<Block> {
+        match op {
+            b"begincodespacerange" => Some(Block::CodespaceRange),
+            b"beginbfchar" => Some(Block::BfChar),
+            b"beginbfrange" => Some(Block::BfRange),
+            b"begincidchar" => Some(Block::CidChar),
+            b"begincidrange" => Some(Block::CidRange),
+            b"beginnotdefchar" => Some(Block::NotdefChar),
+            b"beginnotdefrange" => Some(Block::NotdefRange),
+            _ => None
+        }
+    }
+
+    /// The name of the block, as used by its `begin` and `end` operators
+    fn name(self) -> &'static str {
+        match self {
+            Block::CodespaceRange => "codespacerange",
+            Block::BfChar => "bfchar",
+            Block::BfRange => "bfrange",
+            Block::CidChar => "cidchar",
+            Block::CidRange => "cidrange",
+            Block::NotdefChar => "notdefchar",
+            Block::NotdefRange => "notdefrange"
+        }
+    }
+
+    fn is_end(self, op: &[u8]) -> bool {
+        op.starts_with(b"end") && op[3..] == *self.name().as_bytes()
+    }
+
+    /// The number of values of an entry
+    fn entry_len(self) -> usize {
+        match self {
+            Block::CodespaceRange | Block::BfChar | Block::CidChar | Block::NotdefChar => 2,
+            Block::BfRange | Block::CidRange | Block::NotdefRange => 3
+        }
+    }
+
+    /// Whether `token` can be the value at `index` of an entry. Every value
+    /// but the last is a character code.
+    fn accepts(self, index: usize, token: &Token) -> bool {
+        let is_string = token.is_string();
+        if index + 1 < self.entry_len() || self == Block::CodespaceRange {
+            return is_string;
+        }
+        match self {
+            Block::BfChar => is_string || matches!(*token, Token::Name(_)),
+            Block::BfRange => is_string || *token == Token::ArrayStart,
+            _ => matches!(*token, Token::Integer(_))
+        }
+    }
+}
+
+/// The values of an entry of a block.
+#[derive(Default)]
+struct Entry<'a> {
+    values: Vec<Located<'a>>,
+    // The elements of the array a `bfrange` entry maps to, if its last value
+    // is the start of an array
+    array: Vec<Located<'a>>
+}
+
+impl<'a> Entry<'a> {
+    fn clear(&mut self) {
+        self.values.clear();
+        self.array.clear();
+    }
+}
+
+/// Parses the tokens of a CMap, reporting its contents to a visitor.
+/// Errors are located at the token being processed when they occur.
+///
+/// A strict parser trusts the entry counts of blocks and fails on the first
+/// problem. A lenient one parses blocks up to their `end` operator, skips
+/// what it can't use and records what it repaired.
+struct Parser<'a, 'v, T, V: ?Sized + 'v> {
+    lexer: T,
+    visitor: &'v mut V,
+    // The position of the token being processed
+    position: Position,
+    // Tokens that were read but are processed again, the last one first
+    pending: Vec<Located<'a>>,
+    // `None` when parsing strictly
+    repairs: Option<Vec<Repair>>,
+    // `None` when ignored content isn't reported
+    diagnostics: Option<Vec<Diagnostic>>,
+    options: ParseOptions,
+    // The number of mappings reported so far
+    mappings: usize,
+    interpreter: Interpreter<'a>,
+    // The dictionary that is current at `begincmap`. Before it, definitions
+    // in any dictionary describe the CMap, as CMaps embedded in PDF files
+    // often leave out the PostScript around the CMap.
+    cmap_dictionary: Option<usize>
+}
+
+impl<'a, 'v, T, V> Parser<'a, 'v, T, V>
+    where T: Tokens<'a>, V: CMapVisitor + ?Sized
+{
+    fn new(lexer: T, visitor: &'v mut V) -> Parser<'a, 'v, T, V> {
+        Parser {
+            lexer,
+            visitor,
+            position: Position::default(),
+            pending: Vec::new(),
+            repairs: None,
+            diagnostics: None,
+            options: ParseOptions::unlimited(),
+            mappings: 0,
+            interpreter: Interpreter::new(),
+            cmap_dictionary: None
+        }
+    }
+
+    fn is_lenient(&self) -> bool {
+        self.repairs.is_some()
+    }
+
+    fn parse(&mut self) -> Result<()> {
+        let error = match self.parse_tokens() {
+            Ok(()) => return self.header_comments(),
+            Err(e) => e.locate(self.position.into(), |offset| self.lexer.snippet(offset))
+        };
+        match error {
+            CMapError::Lexer { .. } if self.is_lenient() => {
+                self.repair_error(RepairKind::Truncated, error);
+                self.header_comments()
+            },
+            _ => Err(error)
+        }
+    }
+
+    /// Reports the DSC comments at the start of the file.
+    fn header_comments(&mut self) -> Result<()> {
+        for comment in self.lexer.take_header_comments() {
+            if let Some(metadata) = dsc_comment(&comment) {
+                self.visitor.metadata(metadata)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn location(&self, position: Position) -> Location {
+        let mut location = Location::from(position);
+        location.snippet = self.lexer.snippet(position.offset);
+        location
+    }
+
+    fn repair(&mut self, kind: RepairKind, message: String, position: Position) {
+        let location = self.location(position);
+        if let Some(ref mut repairs) = self.repairs {
+            repairs.push(Repair { kind, message, location });
+        }
+    }
+
+    fn repair_error(&mut self, kind: RepairKind, error: CMapError) {
+        let error = error.locate(self.position.into(), |offset| self.lexer.snippet(offset));
+        let (message, location) = error.into_message();
+        if let Some(ref mut repairs) = self.repairs {
+            repairs.push(Repair { kind, message, location });
+        }
+    }
+
+    fn warn(&mut self, kind: DiagnosticKind, message: String, position: Position) {
+        if self.diagnostics.is_none() {
+            return;
+        }
+        let location = self.location(position);
+        if let Some(ref mut diagnostics) = self.diagnostics {
+            diagnostics.push(Diagnostic { kind, message, location });
+        }
+    }
+
+    fn check_limit(&self, limit: Limit, value: usize) -> Result<()> {
+        let max = self.options.max(limit);
+        if value > max {
+            return Err(CMapError::LimitExceeded { limit, max, location: Location::default() });
+        }
+        Ok(())
+    }
+
+    fn add_mappings(&mut self, count: usize) -> Result<()> {
+        self.mappings = self.mappings.saturating_add(count);
+        self.check_limit(Limit::Mappings, self.mappings)
+    }
+
+    /// Returns the next token, keeping track of its position.
+    fn next_token(&mut self) -> Result<Option<Token<'a>>> {
+        let next = match self.pending.pop() {
+            Some(pending) => Some(Ok(pending)),
+            None => self.lexer.next()
+        };
+        match next {
+            Some(Ok((token, position))) => {
+                self.position = position;
+                Ok(Some(token))
+            },
+            Some(Err(e)) => Err(e),
+            None => Ok(None)
+        }
+    }
+
+    /// Returns the next token, which has to exist.
+    fn expect_token(&mut self) -> Result<Token<'a>> {
+        match self.next_token()? {
+            Some(token) => Ok(token),
+            None => Err(CMapError::UnexpectedEndOfInput { location: self.lexer.end_position().into() })
+        }
+    }
+
+    fn parse_tokens(&mut self) -> Result<()> {
+        // The number of open arrays, dictionaries and procedures
+        let mut depth: usize = 0;
+
+        while let Some(token) = self.next_token()? {
+            let result = match token {
+                Token::Operator(ref op) if op[..] == b"endcmap"[..] && !self.interpreter.in_procedure() => break,
+                Token::Operator(op) if !self.interpreter.in_procedure() => self.operator(&op),
+                operand => {
+                    match operand {
+                        Token::ArrayStart | Token::DictionaryStart | Token::ProcedureStart => {
+                            depth += 1;
+                            self.check_limit(Limit::NestingDepth, depth)?;
+                        },
+                        Token::ArrayEnd | Token::DictionaryEnd | Token::ProcedureEnd => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+                    self.interpreter.push(operand, self.position)
+                }
+            };
+            match result {
+                Ok(()) => {},
+                Err(e @ CMapError::Lexer { .. }) => return Err(e),
+                Err(e) => {
+                    if !self.is_lenient() || e.location().is_none() {
+                        return Err(e);
                     }
+                    self.repair_error(RepairKind::IgnoredOperator, e);
                 }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pops the operand of `op`, which errors are then reported at.
+    fn pop_operand(&mut self, op: &[u8]) -> Result<Object<'a>> {
+        let (operand, position) = self.interpreter.pop(op)?;
+        self.position = position;
+        Ok(operand)
+    }
+
+    fn operator(&mut self, op: &[u8]) -> Result<()> {
+        if let Some(block) = Block::from_begin(op) {
+            return if self.is_lenient() {
+                let declared = match self.interpreter.top() {
+                    Some(&(Object::Simple(Token::Integer(size)), _)) => Some(size),
+                    _ => None
+                };
+                if declared.is_some() {
+                    self.interpreter.pop(op)?;
+                }
+                self.lenient_block(block, declared)
+            } else {
+                let size = match self.pop_operand(op)? {
+                    Object::Simple(Token::Integer(size)) => size,
+                    other => return other.expect_type("Integer")
+                };
+                if size < 0 {
+                    return Err(CMapError::CMapType {
+                        expected: "non-negative Integer",
+                        found: "negative Integer",
+                        location: Location::default()
+                    });
+                }
+                if block != Block::CodespaceRange {
+                    self.check_limit(Limit::Mappings, self.mappings.saturating_add(size as usize))?;
+                }
+                self.strict_block(block, size)
+            };
+        }
+        let op_position = self.position;
+        match op {
+            b"usecmap" => {
+                // Embedded CMaps use a name, but older files in the wild use a string
+                let other_cmap = match self.pop_operand(op)? {
+                    Object::Simple(ref s) if s.is_string() => s.as_string()?.into_owned(),
+                    Object::Simple(ref name @ Token::Name(_)) => name.as_name()?.into_owned(),
+                    other => return other.expect_type("Name")
+                };
+                let name = str::from_utf8(&other_cmap).map_err(|cause| CMapError::Utf8 { cause, location: Location::default() })?;
+                self.warn(DiagnosticKind::IgnoredUseCMap, format!("The CMap {} used with usecmap was ignored", name), op_position);
+                // Errors resolving the CMap are reported at the operator
+                self.position = op_position;
+                self.visitor.usecmap(name)?;
             },
-            Value::Name(ref s) => {
-                match str::from_utf8(&s).unwrap() {
-                    "WMode" => {
-                        if let Value::Integer(mode) = lexems[i+1] {
-                            cmap.writing_mode = WritingMode::from(mode != 0);
-                        }
-                    },
-                    "CMapName" => {
-                        if let Value::Name(ref name) = lexems[i+1] {
-                            cmap.name = String::from(str::from_utf8(name).unwrap());
-                        }
-                    },
-                    "CMapVersion" => {
-                        if let Value::Integer(version) = lexems[i+1] {
-                            cmap.version = version.to_string();
-                        }
-                        if let Value::LiteralString(ref version) = lexems[i+1] {
-                            cmap.version = String::from(str::from_utf8(version).unwrap());
+            b"begincmap" => self.cmap_dictionary = Some(self.interpreter.current_dictionary()),
+            b"begin" => {
+                // Names are looked up through the whole dictionary stack
+                self.check_limit(Limit::NestingDepth, self.interpreter.dictionary_depth() + 1)?;
+                self.interpreter.execute(op, op_position)?;
+            },
+            b"def" => {
+                if let Some(definition) = self.interpreter.def(op)? {
+                    if self.cmap_dictionary.map_or(true, |dictionary| dictionary == definition.dictionary) {
+                        self.define(definition)?;
+                    }
+                }
+            },
+            _ => {
+                if !self.interpreter.execute(op, op_position)? && self.diagnostics.is_some() && !KNOWN_OPERATORS.contains(&op) {
+                    let message = format!("The operator {} is unknown", String::from_utf8_lossy(op));
+                    self.warn(DiagnosticKind::UnknownOperator, message, op_position);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports a definition in the dictionary of the CMap to the visitor.
+    fn define(&mut self, definition: Definition<'a>) -> Result<()> {
+        let Definition { key, key_position, value, value_position, .. } = definition;
+        if key == b"CIDSystemInfo" {
+            // CMaps for rearranged fonts have an array with a dictionary per
+            // font, the first one describes the CMap itself
+            let info = match value {
+                Object::Dictionary(index) => Some(index),
+                Object::Array(index) => match self.interpreter.array(index).first() {
+                    Some(&Object::Dictionary(index)) => Some(index),
+                    _ => None
+                },
+                _ => None
+            };
+            if let Some(info) = info {
+                let mut unknown: Vec<_> = self.interpreter.dictionary(info).iter()
+                    .filter(|&(entry_key, _)| !CID_SYSTEM_INFO_KEYS.contains(&&entry_key[..]))
+                    .map(|(entry_key, &(_, entry_position))| (entry_key.clone(), entry_position))
+                    .collect();
+                // The order of the dictionary isn't that of the file
+                unknown.sort_by_key(|&(_, entry_position)| entry_position.offset);
+                for (entry_key, entry_position) in unknown {
+                    let message = format!("The key /{} of /CIDSystemInfo is unknown", String::from_utf8_lossy(&entry_key));
+                    self.warn(DiagnosticKind::UnknownKey, message, entry_position);
+                }
+                for &entry_key in CID_SYSTEM_INFO_KEYS {
+                    let entry = self.interpreter.dictionary(info).get(entry_key).cloned();
+                    if let Some((entry, entry_position)) = entry {
+                        self.define(Definition {
+                            key: entry_key.to_vec(),
+                            key_position: entry_position,
+                            value: entry,
+                            value_position: entry_position,
+                            dictionary: info
+                        })?;
+                    }
+                }
+                return Ok(());
+            }
+        }
+        self.position = value_position;
+        let metadata = match value {
+            // The version is kept as written, so that `1.0` doesn't become `1`
+            Object::Simple(Token::Number(version)) if key == b"CMapVersion" => {
+                Ok(Some(Metadata::Version(self.number_text(version, value_position))))
+            },
+            _ => metadata(&key, &value, &self.interpreter)
+        };
+        match metadata {
+            Ok(Some(metadata)) => self.visitor.metadata(metadata)?,
+            Ok(None) if !KNOWN_KEYS.contains(&&key[..]) => {
+                let name = String::from_utf8_lossy(&key).into_owned();
+                self.warn(DiagnosticKind::UnknownKey, format!("The key /{} is unknown", name), key_position);
+                let value = extra_value(&value, &self.interpreter, false)?;
+                self.visitor.metadata(Metadata::Other(name, value))?;
+            },
+            Ok(None) => {},
+            Err(CMapError::CMapType { expected, found, .. }) => {
+                let message = format!("The value of /{} has the type {}, but expected {}", String::from_utf8_lossy(&key), found, expected);
+                self.warn(DiagnosticKind::UnexpectedType, message, value_position);
+            },
+            Err(e) => return Err(e)
+        }
+        Ok(())
+    }
+
+    /// Returns the text of `number` as found at `position`. It's formatted
+    /// from the value if the text is no longer available, or if the number
+    /// was the value of a name found there.
+    fn number_text(&self, number: f64, position: Position) -> String {
+        self.lexer.keyword_text(position.offset)
+            .and_then(|text| str::from_utf8(text).ok())
+            .filter(|text| text.parse() == Ok(number))
+            .map_or_else(|| number.to_string(), str::to_owned)
+    }
+
+    /// Parses the number of entries the block declares.
+    fn strict_block(&mut self, block: Block, size: i64) -> Result<()> {
+        let mut entry = Entry::default();
+        for _ in 0..(size as usize) {
+            entry.clear();
+            for index in 0..block.entry_len() {
+                let token = self.expect_token()?;
+                let is_array = block == Block::BfRange && index == 2 && token == Token::ArrayStart;
+                entry.values.push((token, self.position));
+                if is_array {
+                    loop {
+                        let element = self.expect_token()?;
+                        if element == Token::ArrayEnd {
+                            break;
                         }
-                    },
-                    "CMapType" => {
-                        if let Value::Integer(cmap_type) = lexems[i+1] {
-                            cmap.cmap_type = cmap_type;
+                        entry.array.push((element, self.position));
+                        self.check_limit(Limit::Mappings, self.mappings.saturating_add(entry.array.len()))?;
+                    }
+                }
+            }
+            self.visit_entry(block, &entry)?;
+        }
+        Ok(())
+    }
+
+    /// Parses the entries up to the end of the block, whatever number of
+    /// entries it declares.
+    fn lenient_block(&mut self, block: Block, declared: Option<i64>) -> Result<()> {
+        let begin_position = self.position;
+        let mut entry = Entry::default();
+        let mut found = 0;
+        // Tokens that can't be part of an entry, which are only skipped once
+        // it is clear they aren't the operands of an operator ending the block
+        let mut strays = Vec::new();
+
+        loop {
+            let token = match self.next_token()? {
+                Some(token) => token,
+                None => {
+                    let end_position = self.lexer.end_position();
+                    self.repair(RepairKind::MissingEnd, format!("The {} block isn't closed", block.name()), end_position);
+                    break;
+                }
+            };
+            let position = self.position;
+            match token {
+                Token::Operator(ref op) if block.is_end(op) => break,
+                Token::Operator(_) => {
+                    // Most likely the operator of the next block, which
+                    // is processed as usual
+                    self.pending.push((token, position));
+                    self.pending.extend(strays.drain(..).rev());
+                    self.repair(RepairKind::MissingEnd, format!("The {} block isn't closed", block.name()), position);
+                    break;
+                },
+                _ => {}
+            }
+
+            let index = entry.values.len();
+            let fits = block.accepts(index, &token);
+            let starts_entry = index > 0 && block.accepts(0, &token);
+            if !fits && !starts_entry {
+                strays.push((token, position));
+                continue;
+            }
+            self.skip_strays(&mut strays);
+            if fits {
+                let is_array = token == Token::ArrayStart;
+                entry.values.push((token, position));
+                if is_array {
+                    self.lenient_array(&mut entry.array)?;
+                }
+                if entry.values.len() == block.entry_len() {
+                    found += 1;
+                    if let Err(e) = self.visit_entry(block, &entry) {
+                        if e.location().is_none() {
+                            return Err(e);
                         }
+                        self.repair_error(RepairKind::SkippedEntry, e);
+                    }
+                    entry.clear();
+                }
+            } else {
+                // The entry is missing values and this token starts the next one
+                self.repair(RepairKind::SkippedEntry, "Skipped an incomplete entry".to_owned(), entry.values[0].1);
+                entry.clear();
+                entry.values.push((token, position));
+            }
+        }
 
+        self.skip_strays(&mut strays);
+        if let Some(&(_, position)) = entry.values.first() {
+            self.repair(RepairKind::SkippedEntry, "Skipped an incomplete entry".to_owned(), position);
+        }
+        if declared != Some(found) {
+            let message = match declared {
+                Some(declared) => format!("The {} block declares {} entries, but has {}", block.name(), declared, found),
+                None => format!("The {} block doesn't declare its number of entries", block.name())
+            };
+            self.repair(RepairKind::EntryCount, message, begin_position);
+        }
+        Ok(())
+    }
+
+    fn skip_strays(&mut self, strays: &mut Vec<Located<'a>>) {
+        for (token, position) in strays.drain(..) {
+            self.repair(RepairKind::SkippedToken, format!("Skipped a stray {}", token.get_type()), position);
+        }
+    }
+
+    /// Reads the elements of an array up to its end. If the end is missing,
+    /// the array ends before the next operator.
+    fn lenient_array(&mut self, array: &mut Vec<Located<'a>>) -> Result<()> {
+        while let Some(token) = self.next_token()? {
+            match token {
+                Token::ArrayEnd => break,
+                Token::Operator(_) => {
+                    self.pending.push((token, self.position));
+                    break;
+                },
+                element => array.push((element, self.position))
+            }
+        }
+        Ok(())
+    }
+
+    fn char_code(&mut self, value: &Located<'a>) -> Result<CharCode> {
+        self.position = value.1;
+        char_code(&value.0)
+    }
+
+    fn cid(&mut self, value: &Located<'a>) -> Result<Cid> {
+        self.position = value.1;
+        Ok(value.0.as_integer()? as Cid)
+    }
+
+    fn check_range_width(&self, low_code: CharCode, high_code: CharCode) -> Result<()> {
+        let width = high_code.value.checked_sub(low_code.value).map_or(0, |d| d as usize + 1);
+        self.check_limit(Limit::RangeWidth, width)
+    }
+
+    /// Decodes the destination of a mapping. Mappings to invalid UTF-16
+    /// are found in ToUnicode CMaps in the wild, they are skipped rather
+    /// than failing the whole CMap.
+    fn unicode(&mut self, value: &Located<'a>) -> Result<Option<String>> {
+        self.position = value.1;
+        let unicode = utf16be_to_string(&value.0.as_string()?);
+        if unicode.is_none() {
+            let message = "Skipped a mapping to invalid UTF-16".to_owned();
+            self.warn(DiagnosticKind::InvalidUnicode, message.clone(), value.1);
+            self.repair(RepairKind::SkippedEntry, message, value.1);
+        }
+        Ok(unicode)
+    }
+
+    /// Reports a complete entry of a block to the visitor.
+    fn visit_entry(&mut self, block: Block, entry: &Entry<'a>) -> Result<()> {
+        let values = &entry.values;
+        match block {
+            Block::CodespaceRange => {
+                let low_code = self.char_code(&values[0])?;
+                let high_code = self.char_code(&values[1])?;
+                self.visitor.codespace_range(low_code, high_code)
+            },
+            Block::CidChar | Block::NotdefChar => {
+                let char_code = self.char_code(&values[0])?;
+                let cid = self.cid(&values[1])?;
+                self.add_mappings(1)?;
+                if block == Block::CidChar {
+                    self.visitor.cid_char(char_code, cid)
+                } else {
+                    self.visitor.notdef_char(char_code, cid)
+                }
+            },
+            Block::CidRange | Block::NotdefRange => {
+                let low_code = self.char_code(&values[0])?;
+                let high_code = self.char_code(&values[1])?;
+                self.check_range_width(low_code, high_code)?;
+                let start = self.cid(&values[2])?;
+                self.add_mappings(1)?;
+                if block == Block::CidRange {
+                    self.visitor.cid_range(low_code, high_code, start)
+                } else {
+                    self.visitor.notdef_range(low_code, high_code, start)
+                }
+            },
+            Block::BfChar => {
+                let char_code = self.char_code(&values[0])?;
+                self.position = values[1].1;
+                let unicode = match values[1].0 {
+                    ref output_code if output_code.is_string() => match self.unicode(&values[1])? {
+                        Some(unicode) => unicode,
+                        None => return Ok(())
                     },
-                    "Registry" => {
-                        if let Value::LiteralString(ref registry) = lexems[i+1] {
-                            cmap.registry = String::from(str::from_utf8(registry).unwrap());
-                        }
+                    ref output_name @ Token::Name(_) => to_string(output_name.as_name()?)?,
+                    ref output => return output.expect_type("Literal String or Name")
+                };
+                self.add_mappings(1)?;
+                self.visitor.bf_char(char_code, unicode)
+            },
+            Block::BfRange => {
+                let low_code = self.char_code(&values[0])?;
+                let high_code = self.char_code(&values[1])?;
+                let (ref start, start_position) = values[2];
+                match *start {
+                    _ if start.is_string() => {
+                        self.check_range_width(low_code, high_code)?;
+                        let start = match self.unicode(&values[2])? {
+                            Some(start) => start,
+                            None => return Ok(())
+                        };
+                        self.add_mappings(1)?;
+                        self.visitor.bf_range(low_code, high_code, start)
                     },
-                    "Ordering" => {
-                        if let Value::LiteralString(ref ordering) = lexems[i+1] {
-                            cmap.ordering = String::from(str::from_utf8(ordering).unwrap());
+                    Token::ArrayStart => {
+                        let unicodes = entry.array.iter().map(|element| self.unicode(element)).collect::<Result<Vec<_>>>()?;
+                        let expected_len = high_code.value.checked_sub(low_code.value).map_or(0, |d| d as usize + 1);
+                        if expected_len != unicodes.len() {
+                            return Err(CMapError::InvalidArrayLength {
+                                expected: expected_len,
+                                found: unicodes.len(),
+                                location: start_position.into()
+                            });
                         }
-                    },
-                    "Supplement" => {
-                        if let Value::Integer(supplement) = lexems[i+1] {
-                            cmap.supplement = supplement as u32;
+                        self.add_mappings(unicodes.len())?;
+                        for (i, unicode) in unicodes.into_iter().enumerate() {
+                            if let Some(unicode) = unicode {
+                                self.visitor.bf_char(CharCode::new(low_code.value.wrapping_add(i as u32), low_code.len), unicode)?;
+                            }
                         }
+                        Ok(())
                     },
-                    _ => { if i > 0 { i -= 1; } /* Since we didn't consume an argument */}
+                    _ => {
+                        self.position = start_position;
+                        start.expect_type("Literal String or Array")
+                    }
                 }
-                i += 2;
             }
-            _ => { i += 1; }
         }
     }
-
-    Ok(cmap)
 }
 
 /*pub fn get_unicode_map(input: &[u8]) -> Result<HashMap<u32, u32>, &'static str> {
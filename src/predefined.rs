@@ -0,0 +1,83 @@
+use flate2::read::DeflateDecoder;
+use std::io::Read;
+use std::sync::{Arc, OnceLock};
+
+use ::CMap;
+use error::{Result, CMapError, Location};
+use parser::parse_cmap_with_resolver;
+use resolver::{CMapCache, CMapResolver};
+
+// Generated by build.rs: the name and deflate-compressed contents of every
+// CMap in assets/adobe_cmaps, sorted by name.
+include!(concat!(env!("OUT_DIR"), "/predefined.rs"));
+
+fn cache() -> &'static CMapCache<&'static str> {
+    static CACHE: OnceLock<CMapCache<&'static str>> = OnceLock::new();
+    CACHE.get_or_init(CMapCache::new)
+}
+
+fn find(name: &str) -> Option<(&'static str, &'static [u8])> {
+    PREDEFINED.binary_search_by(|&(n, _)| n.cmp(name))
+        .ok()
+        .map(|i| PREDEFINED[i])
+}
+
+/// Resolves `usecmap` references against the embedded CMaps.
+struct PredefinedResolver;
+
+impl CMapResolver for PredefinedResolver {
+    fn resolve(&self, name: &str) -> Result<Vec<u8>> {
+        let (_, compressed) = find(name).ok_or_else(|| CMapError::CMapNotFound { name: name.to_owned(), location: Location::default() })?;
+        let mut contents = Vec::new();
+        DeflateDecoder::new(compressed).read_to_end(&mut contents).map_err(CMapError::Io)?;
+        Ok(contents)
+    }
+
+    fn resolve_cmap(&self, name: &str, parse: &mut dyn FnMut(&[u8]) -> Result<CMap>) -> Result<Arc<CMap>> {
+        load(name, parse)
+    }
+}
+
+/// Returns the predefined CMap called `name` from the cache, parsing it
+/// with `parse` the first time.
+fn load(name: &str, parse: &mut dyn FnMut(&[u8]) -> Result<CMap>) -> Result<Arc<CMap>> {
+    let (name, _) = find(name).ok_or_else(|| CMapError::CMapNotFound { name: name.to_owned(), location: Location::default() })?;
+    cache().get_or_parse(name, |name| PredefinedResolver.resolve(name), parse)
+}
+
+impl CMap {
+    /// Returns one of the predefined CMaps from `assets/adobe_cmaps`, such
+    /// as `UniJIS-UCS2-H` or `GBK-EUC-H`, with its `usecmap` references
+    /// resolved.
+    ///
+    /// The CMap is parsed the first time it is requested and shared
+    /// afterwards. `None` is returned for unknown names.
+    pub fn predefined(name: &str) -> Option<Arc<CMap>> {
+        load(name, &mut |contents| parse_cmap_with_resolver(contents, &PredefinedResolver)).ok()
+    }
+
+    /// Returns the names of all predefined CMaps in alphabetical order.
+    pub fn predefined_names() -> impl Iterator<Item = &'static str> {
+        PREDEFINED.iter().map(|&(name, _)| name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::CharCode;
+
+    #[test]
+    fn predefined() {
+        assert_eq!(CMap::predefined_names().count(), 91);
+        assert!(CMap::predefined_names().any(|name| name == "UniJIS-UCS2-H"));
+        assert!(CMap::predefined("NotACMap").is_none());
+
+        let cmap = CMap::predefined("90ms-RKSJ-V").unwrap();
+        assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x8141, 2)), 7887);
+        assert_eq!(cmap.codepoint_to_cid(CharCode::new(0x8260, 2)), 790);
+        assert!(Arc::ptr_eq(&cmap, &CMap::predefined("90ms-RKSJ-V").unwrap()));
+        // The used CMap is cached as well
+        assert!(cache().contains(&"90ms-RKSJ-H"));
+    }
+}
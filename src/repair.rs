@@ -0,0 +1,36 @@
+use error::Location;
+use std::fmt;
+
+/// What was wrong with a CMap parsed by `parse_cmap_lenient`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RepairKind {
+    /// A block has a different number of entries than it declares, or
+    /// doesn't declare how many it has. All of its entries were parsed.
+    EntryCount,
+    /// A block isn't closed by its `end` operator. It was closed at the next
+    /// operator or at the end of the input.
+    MissingEnd,
+    /// An entry of a block is incomplete or invalid and was skipped.
+    SkippedEntry,
+    /// A token that can't be part of an entry was skipped.
+    SkippedToken,
+    /// An operator outside of a block has invalid operands and was ignored.
+    IgnoredOperator,
+    /// The rest of the input couldn't be tokenized, e.g. because the file
+    /// was truncated in the middle of a string. Parsing stopped there.
+    Truncated,
+}
+
+/// A problem with a CMap that was worked around by `parse_cmap_lenient`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Repair {
+    pub kind: RepairKind,
+    pub message: String,
+    pub location: Location,
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.location)
+    }
+}
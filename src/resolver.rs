@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+use ::CMap;
+use error::Result;
+
+/// The maximum number of nested `usecmap` references followed by
+/// `parse_cmap_with_resolver` before giving up.
+pub const MAX_USECMAP_DEPTH: usize = 16;
+
+/// Provides the source of CMaps referenced through `usecmap`.
+///
+/// A CMap such as `90ms-RKSJ-V` only contains the vertical variants of its
+/// glyphs and pulls in everything else with `/90ms-RKSJ-H usecmap`. When
+/// parsing with `parse_cmap_with_resolver`, the resolver is asked for the
+/// contents of the referenced CMap, which is then parsed (resolving its own
+/// `usecmap` references through the same resolver) and merged underneath
+/// the referencing one.
+pub trait CMapResolver {
+    /// Returns the unparsed contents of the CMap called `name`.
+    fn resolve(&self, name: &str) -> Result<Vec<u8>>;
+
+    /// Returns the CMap called `name`, parsed from its contents with
+    /// `parse`. By default every reference is parsed again, resolvers that
+    /// keep parsed CMaps around can return those instead.
+    fn resolve_cmap(&self, name: &str, parse: &mut dyn FnMut(&[u8]) -> Result<CMap>) -> Result<Arc<CMap>> {
+        parse(&self.resolve(name)?).map(Arc::new)
+    }
+}
+
+impl<F> CMapResolver for F where F: Fn(&str) -> Result<Vec<u8>> {
+    fn resolve(&self, name: &str) -> Result<Vec<u8>> {
+        self(name)
+    }
+}
+
+/// The parsed CMaps of a resolver, shared with the CMaps using them.
+pub(crate) struct CMapCache<K> {
+    cmaps: Mutex<HashMap<K, Arc<CMap>>>,
+}
+
+impl<K: Eq + Hash> CMapCache<K> {
+    pub fn new() -> CMapCache<K> {
+        CMapCache { cmaps: Mutex::new(HashMap::new()) }
+    }
+
+    #[cfg(test)]
+    pub fn contains(&self, key: &K) -> bool {
+        self.cmaps.lock().unwrap().contains_key(key)
+    }
+
+    /// Returns the CMap cached under `key`, or parses the contents
+    /// `contents` returns for the key with `parse` and caches it.
+    pub fn get_or_parse<F>(&self, key: K, contents: F, parse: &mut dyn FnMut(&[u8]) -> Result<CMap>) -> Result<Arc<CMap>>
+        where F: FnOnce(&K) -> Result<Vec<u8>>
+    {
+        if let Some(cmap) = self.cmaps.lock().unwrap().get(&key) {
+            return Ok(cmap.clone());
+        }
+
+        // The lock isn't held while parsing, which loads the used CMaps
+        // through the same cache
+        let cmap = Arc::new(parse(&contents(&key)?)?);
+        Ok(self.cmaps.lock().unwrap().entry(key).or_insert(cmap).clone())
+    }
+}
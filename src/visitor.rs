@@ -0,0 +1,94 @@
+use error::Result;
+use metadata::Value;
+use {CharCode, Cid, WritingMode};
+
+/// An entry describing the CMap as a whole, as set with `def`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Metadata {
+    /// `/CMapName`
+    Name(String),
+    /// `/CMapVersion`
+    Version(String),
+    /// `/CMapType`
+    CMapType(i64),
+    /// `/WMode`
+    WritingMode(WritingMode),
+    /// `/Registry` of the `/CIDSystemInfo`
+    Registry(String),
+    /// `/Ordering` of the `/CIDSystemInfo`
+    Ordering(String),
+    /// `/Supplement` of the `/CIDSystemInfo`
+    Supplement(u32),
+    /// `/UIDOffset`
+    UidOffset(i64),
+    /// `/XUID`
+    Xuid(Vec<i64>),
+    /// `/CIDCount`
+    CidCount(u32),
+    /// The text of the `%%Title` comment
+    Title(String),
+    /// The version of the `%%Version` comment
+    DscVersion(f64),
+    /// The category and the name of the `%%BeginResource` comment
+    Resource(String, String),
+    /// A key that isn't part of the CMap format
+    Other(String, Value),
+}
+
+/// Receives the contents of a CMap while it is parsed by `visit_cmap`.
+///
+/// This allows processing the mappings of a CMap one by one, e.g. to
+/// convert them to another format, without building a `CMap`. Every method
+/// does nothing by default. An error returned by a method stops parsing and
+/// is passed on to the caller of `visit_cmap`.
+pub trait CMapVisitor {
+    /// A range of a `codespacerange` block.
+    fn codespace_range(&mut self, _low: CharCode, _high: CharCode) -> Result<()> {
+        Ok(())
+    }
+
+    /// An entry of a `cidchar` block.
+    fn cid_char(&mut self, _code: CharCode, _cid: Cid) -> Result<()> {
+        Ok(())
+    }
+
+    /// An entry of a `cidrange` block, mapping `low` to `cid` and every
+    /// following code up to `high` to the following CID.
+    fn cid_range(&mut self, _low: CharCode, _high: CharCode, _cid: Cid) -> Result<()> {
+        Ok(())
+    }
+
+    /// An entry of a `notdefchar` block.
+    fn notdef_char(&mut self, _code: CharCode, _cid: Cid) -> Result<()> {
+        Ok(())
+    }
+
+    /// An entry of a `notdefrange` block, mapping every code from `low` to
+    /// `high` to `cid`.
+    fn notdef_range(&mut self, _low: CharCode, _high: CharCode, _cid: Cid) -> Result<()> {
+        Ok(())
+    }
+
+    /// An entry of a `bfchar` block. Entries of a `bfrange` block whose
+    /// destination is an array are reported here as well, one per code.
+    fn bf_char(&mut self, _code: CharCode, _unicode: String) -> Result<()> {
+        Ok(())
+    }
+
+    /// An entry of a `bfrange` block, mapping `low` to `start` and every
+    /// following code up to `high` to `start` with its last character
+    /// incremented accordingly.
+    fn bf_range(&mut self, _low: CharCode, _high: CharCode, _start: String) -> Result<()> {
+        Ok(())
+    }
+
+    /// A reference to another CMap with `usecmap`.
+    fn usecmap(&mut self, _name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// An entry describing the CMap.
+    fn metadata(&mut self, _metadata: Metadata) -> Result<()> {
+        Ok(())
+    }
+}